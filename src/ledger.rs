@@ -0,0 +1,533 @@
+use crate::parser::accounts::{
+    Account, Accounts, AdminPolicy, Applied, DisputePolicy, FatalError, OutputFormat, SortKey,
+    TxIdScope, TxRejection,
+};
+use crate::parser::journal::Journal;
+use crate::parser::reader::{ParseMode, Row, TransactionReader};
+use crate::parser::stats::Summary;
+use crate::parser::store::{InMemoryTxStore, TxStore};
+use crate::parser::transactions::Transaction;
+use crate::parser::verify::Violation;
+use anyhow::{anyhow, Result};
+use std::collections::HashSet;
+use std::fmt::Display;
+use std::io::{BufRead, Read, Write};
+use std::panic;
+use std::str::FromStr;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+
+/// rows handed to a worker at a time by [`Ledger::process_csv_parallel`]
+const BATCH_LEN: usize = 1024;
+/// batches queued per worker before the reader waits for it to catch up
+const QUEUED_BATCHES: usize = 4;
+
+/// A moment in the history of a ledger, see [`Ledger::process_reader_until`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AsOf {
+    /// right after the first row with this tx id, e.g. the deposit that claimed it
+    Tx(u32),
+    /// right after this many rows, counted since the ledger was created or loaded,
+    /// across inputs and including malformed rows
+    Row(u64),
+}
+
+impl FromStr for AsOf {
+    type Err = anyhow::Error;
+
+    /// `tx:<id>` or `row:<n>`
+    fn from_str(s: &str) -> Result<Self> {
+        let parsed = match s.split_once(':') {
+            Some(("tx", id)) => id.parse().map(AsOf::Tx).ok(),
+            Some(("row", n)) => n.parse().map(AsOf::Row).ok(),
+            _ => None,
+        };
+        parsed.ok_or_else(|| anyhow!("invalid moment {:?}, expected tx:<id> or row:<n>", s))
+    }
+}
+
+/// A ledger of client accounts. This is the entry point for embedding the engine.
+///
+/// Transactions are applied one at a time with [`Ledger::process`], or read from
+/// csv with [`Ledger::process_csv`] (any input format with [`Ledger::process_reader`]).
+/// The final state is written with [`Ledger::write_csv`] or [`Ledger::write_sorted`].
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Ledger<S = InMemoryTxStore> {
+    accounts: Accounts<S>,
+    parse_mode: ParseMode,
+}
+
+impl Ledger {
+    /// creates an empty ledger that keeps transaction history in memory
+    pub fn new() -> Self {
+        Ledger::from_accounts(Accounts::new())
+    }
+}
+
+impl<S: TxStore> Ledger<S> {
+    /// creates an empty ledger that keeps transaction history in `store`
+    pub fn with_store(store: S) -> Self {
+        Ledger::from_accounts(Accounts::with_store(store))
+    }
+
+    /// creates a ledger on top of existing accounts
+    pub fn from_accounts(accounts: Accounts<S>) -> Self {
+        Ledger {
+            accounts,
+            parse_mode: ParseMode::default(),
+        }
+    }
+
+    /// sets what happens to malformed rows read by [`Ledger::process_reader`], see [`ParseMode`]
+    pub fn with_parse_mode(self, parse_mode: ParseMode) -> Self {
+        Ledger { parse_mode, ..self }
+    }
+
+    /// sets which transactions may be disputed, see [`DisputePolicy`]
+    pub fn with_dispute_policy(self, dispute_policy: DisputePolicy) -> Self {
+        Ledger {
+            accounts: self.accounts.with_dispute_policy(dispute_policy),
+            ..self
+        }
+    }
+
+    /// sets which inputs admin operations are accepted from, see [`AdminPolicy`]
+    pub fn with_admin_policy(self, admin_policy: AdminPolicy) -> Self {
+        Ledger {
+            accounts: self.accounts.with_admin_policy(admin_policy),
+            ..self
+        }
+    }
+
+    /// sets whether tx ids are unique across the ledger or per client, see [`TxIdScope`]
+    pub fn with_tx_id_scope(self, scope: TxIdScope) -> Self {
+        Ledger {
+            accounts: self.accounts.with_tx_id_scope(scope),
+            ..self
+        }
+    }
+
+    /// checks the invariants of an account after every transaction applied to it,
+    /// see [`Accounts::with_verify_each`]
+    pub fn with_verify_each(self, verify_each: bool) -> Self {
+        Ledger {
+            accounts: self.accounts.with_verify_each(verify_each),
+            ..self
+        }
+    }
+
+    /// records every change to an account in `journal`, see [`Accounts::with_journal`]
+    pub fn with_journal(self, journal: Journal) -> Self {
+        Ledger {
+            accounts: self.accounts.with_journal(journal),
+            ..self
+        }
+    }
+
+    /// checks every account and the conservation of money, see [`Accounts::verify`]
+    pub fn verify(&self) -> Result<Vec<Violation>> {
+        self.accounts.verify()
+    }
+
+    /// restores a snapshot written by [`Ledger::save_snapshot`] into this empty ledger
+    pub fn load_snapshot(self, r: impl BufRead) -> Result<Self> {
+        Ok(Ledger {
+            accounts: self.accounts.load_snapshot(r)?,
+            ..self
+        })
+    }
+
+    /// writes the complete state, including transaction history and open disputes,
+    /// so a later run can continue where this one stopped
+    pub fn save_snapshot(&self, w: impl Write) -> Result<()> {
+        self.accounts.save_snapshot(w)
+    }
+
+    /// applies `tx`, see [`Accounts::process_transaction`].
+    /// Check [`Ledger::take_fatal_error`] before going on
+    pub fn process(&mut self, tx: &Transaction) -> Result<Applied, TxRejection> {
+        self.accounts.process_transaction(tx)
+    }
+
+    /// applies `tx` from the admin input, see [`Accounts::process_admin_transaction`]
+    pub fn process_admin(&mut self, tx: &Transaction) -> Result<Applied, TxRejection> {
+        self.accounts.process_admin_transaction(tx)
+    }
+
+    /// the failure that must stop processing after `process`, see [`Accounts::take_fatal_error`]
+    pub fn take_fatal_error(&mut self) -> Option<FatalError> {
+        self.accounts.take_fatal_error()
+    }
+
+    /// applies every row of the csv `input`, see [`Ledger::process_reader`]
+    pub fn process_csv<R: Read>(
+        &mut self,
+        input: R,
+        on_rejected: impl FnMut(&Row, &dyn Display) -> Result<()>,
+    ) -> Result<u64> {
+        self.process_reader(TransactionReader::new(input)?, on_rejected)
+    }
+
+    /// applies every row read by `reader`.
+    /// `on_rejected` is called with each row that was ignored and the reason why.
+    /// Processing stops with an error if the tx store or journal fails, if a transaction breaks
+    /// an invariant with verify-each, or at the first malformed row in strict parse mode;
+    /// `on_rejected` still sees that row first. In quarantine mode,
+    /// malformed rows are also copied to the quarantine of `reader`.
+    /// Returns the number of rows read.
+    pub fn process_reader<R: Read>(
+        &mut self,
+        reader: TransactionReader<R>,
+        on_rejected: impl FnMut(&Row, &dyn Display) -> Result<()>,
+    ) -> Result<u64> {
+        let (rows, _) = self.process_rows(reader, None, false, on_rejected)?;
+        Ok(rows)
+    }
+
+    /// applies every row of the admin input read by `reader`, like [`Ledger::process_reader`];
+    /// admin operations are accepted whatever the [`AdminPolicy`]
+    pub fn process_admin_reader<R: Read>(
+        &mut self,
+        reader: TransactionReader<R>,
+        on_rejected: impl FnMut(&Row, &dyn Display) -> Result<()>,
+    ) -> Result<u64> {
+        let (rows, _) = self.process_rows(reader, None, true, on_rejected)?;
+        Ok(rows)
+    }
+
+    /// applies the rows read by `reader` like [`Ledger::process_reader`], but stops at `as_of`,
+    /// leaving the ledger as it was at that moment.
+    /// Returns the number of rows read and whether `as_of` was reached; if not, every row was
+    /// applied, and the next input may be processed towards the same moment.
+    pub fn process_reader_until<R: Read>(
+        &mut self,
+        reader: TransactionReader<R>,
+        as_of: AsOf,
+        on_rejected: impl FnMut(&Row, &dyn Display) -> Result<()>,
+    ) -> Result<(u64, bool)> {
+        self.process_rows(reader, Some(as_of), false, on_rejected)
+    }
+
+    fn process_rows<R: Read>(
+        &mut self,
+        mut reader: TransactionReader<R>,
+        as_of: Option<AsOf>,
+        admin_input: bool,
+        mut on_rejected: impl FnMut(&Row, &dyn Display) -> Result<()>,
+    ) -> Result<(u64, bool)> {
+        let mut rows = 0;
+        loop {
+            if let Some(AsOf::Row(n)) = as_of {
+                if self.accounts.stats.rows_read() >= n {
+                    return Ok((rows, true));
+                }
+            }
+            let row = match reader.next_row()? {
+                Some(row) => row,
+                None => break,
+            };
+            rows += 1;
+            let tx = match &row.transaction {
+                Ok(tx) => tx,
+                Err(err) => {
+                    self.accounts.stats.malformed += 1;
+                    on_rejected(&row, err)?;
+                    match self.parse_mode {
+                        ParseMode::Strict => anyhow::bail!("line {}: {}", row.line(), err),
+                        ParseMode::Lenient => continue,
+                        ParseMode::Quarantine => {
+                            reader.quarantine(&row)?;
+                            continue;
+                        }
+                    }
+                }
+            };
+            let applied = if admin_input {
+                self.accounts.process_admin_transaction(tx)
+            } else {
+                self.accounts.process_transaction(tx)
+            };
+            if let Err(rejection) = applied {
+                on_rejected(&row, &rejection)?;
+                if rejection.is_fatal() {
+                    return Err(rejection.into());
+                }
+            }
+            if let Some(fatal_error) = self.accounts.take_fatal_error() {
+                return Err(fatal_error.into());
+            }
+            if as_of == Some(AsOf::Tx(tx.tx_id)) {
+                return Ok((rows, true));
+            }
+        }
+        let reached = match as_of {
+            Some(AsOf::Row(n)) => self.accounts.stats.rows_read() >= n,
+            _ => false,
+        };
+        Ok((rows, reached))
+    }
+
+    /// applies every row of the csv `input` on `workers` threads,
+    /// see [`Ledger::process_reader_parallel`]
+    pub fn process_csv_parallel<R: Read>(
+        &mut self,
+        input: R,
+        workers: usize,
+        new_store: impl FnMut(usize) -> Result<S>,
+        on_rejected: impl FnMut(&Row, &dyn Display) -> Result<()>,
+    ) -> Result<u64>
+    where
+        S: Send,
+    {
+        let reader = TransactionReader::new(input)?;
+        self.process_reader_parallel(reader, workers, new_store, on_rejected)
+    }
+
+    /// like [`Ledger::process_reader`], but applies the rows on `workers` threads.
+    /// Clients are split across workers by client id, so each worker owns its clients' accounts
+    /// and applies their rows in input order; the final state is the same as with `process_reader`.
+    /// Every worker keeps the history of its clients in a store from `new_store(worker)`,
+    /// which is merged back into this ledger's store at the end.
+    /// `on_rejected` is called on this thread, but not necessarily in input order.
+    /// Returns the number of rows read.
+    pub fn process_reader_parallel<R: Read>(
+        &mut self,
+        mut reader: TransactionReader<R>,
+        workers: usize,
+        mut new_store: impl FnMut(usize) -> Result<S>,
+        mut on_rejected: impl FnMut(&Row, &dyn Display) -> Result<()>,
+    ) -> Result<u64>
+    where
+        S: Send,
+    {
+        let workers = workers.max(1);
+        // the clients with an account, as `apply` would see them when each row is claimed
+        let mut known_clients: HashSet<u16> = self.accounts.state.keys().copied().collect();
+        // every store is created before any account is moved out of this ledger
+        let stores = (0..workers)
+            .map(&mut new_store)
+            .collect::<Result<Vec<S>>>()?;
+        let mut shards = Vec::with_capacity(workers);
+        for (shard, store) in stores.into_iter().enumerate() {
+            match self.accounts.split_shard(shard, workers, store) {
+                Ok(split) => shards.push(split),
+                Err(err) => {
+                    // put back what was already split, so the ledger loses nothing
+                    for split in shards {
+                        self.accounts.absorb(split)?;
+                    }
+                    return Err(err);
+                }
+            }
+        }
+        // global tx ids span workers, so they are claimed here in input order
+        let claim_globally = self.accounts.seen_tx_ids.scope() == TxIdScope::Global;
+        let parse_mode = self.parse_mode;
+        let accounts = &mut self.accounts;
+        let (mut result, shards) = thread::scope(|scope| {
+            let (rejected_tx, rejected_rx) = mpsc::channel();
+            let mut senders = Vec::with_capacity(workers);
+            let mut handles = Vec::with_capacity(workers);
+            for shard in shards {
+                let (jobs_tx, jobs_rx) = mpsc::sync_channel(QUEUED_BATCHES);
+                let rejected_tx = rejected_tx.clone();
+                senders.push(jobs_tx);
+                handles.push(scope.spawn(move || run_shard(shard, jobs_rx, rejected_tx)));
+            }
+            drop(rejected_tx);
+
+            let mut result = Ok(0);
+            let mut batches: Vec<Vec<Job>> = (0..workers).map(|_| Vec::new()).collect();
+            loop {
+                let row = match reader.next_row() {
+                    Ok(Some(row)) => row,
+                    Ok(None) => break,
+                    Err(err) => {
+                        result = Err(err);
+                        break;
+                    }
+                };
+                if let Ok(rows) = result.as_mut() {
+                    *rows += 1;
+                }
+                let tx = match &row.transaction {
+                    Ok(tx) => *tx,
+                    Err(err) => {
+                        accounts.stats.malformed += 1;
+                        if let Err(err) = on_rejected(&row, err) {
+                            result = Err(err);
+                            break;
+                        }
+                        match parse_mode {
+                            ParseMode::Strict => {
+                                result = Err(anyhow!("line {}: {}", row.line(), err));
+                                break;
+                            }
+                            ParseMode::Lenient => {}
+                            ParseMode::Quarantine => {
+                                if let Err(err) = reader.quarantine(&row) {
+                                    result = Err(err);
+                                    break;
+                                }
+                            }
+                        }
+                        continue;
+                    }
+                };
+                // an admin operation on a client without an account is rejected by its worker
+                // before it claims a tx id, and any other row opens the account
+                let admin = tx.transaction_type.is_admin();
+                let precheck =
+                    if claim_globally && (!admin || known_clients.contains(&tx.client_id)) {
+                        accounts.claim_tx_id(&tx).err()
+                    } else {
+                        None
+                    };
+                if !admin {
+                    known_clients.insert(tx.client_id);
+                }
+                let shard = usize::from(tx.client_id) % workers;
+                batches[shard].push(Job { row, tx, precheck });
+                if batches[shard].len() == BATCH_LEN {
+                    let batch = std::mem::take(&mut batches[shard]);
+                    // a worker only hangs up after a fatal error, which is reported below
+                    if senders[shard].send(batch).is_err() {
+                        break;
+                    }
+                }
+                if let Err(err) = rejected_rx
+                    .try_iter()
+                    .try_for_each(|rejected| report(&mut on_rejected, rejected))
+                {
+                    result = Err(err);
+                    break;
+                }
+            }
+            // rows before an error are still applied, as with `process_reader`
+            for (sender, batch) in senders.into_iter().zip(batches) {
+                if !batch.is_empty() {
+                    let _ = sender.send(batch);
+                }
+            }
+            for rejected in rejected_rx {
+                let reported = report(&mut on_rejected, rejected);
+                if let (Ok(_), Err(err)) = (&result, reported) {
+                    result = Err(err);
+                }
+            }
+            let shards: Vec<Accounts<S>> = handles
+                .into_iter()
+                .map(|handle| {
+                    handle
+                        .join()
+                        .unwrap_or_else(|err| panic::resume_unwind(err))
+                })
+                .collect();
+            (result, shards)
+        });
+        for shard in shards {
+            if let Err(err) = self.accounts.absorb(shard) {
+                result = result.and(Err(err));
+            }
+        }
+        if let Some(fatal_error) = self.accounts.take_fatal_error() {
+            result = result.and(Err(fatal_error.into()));
+        }
+        result
+    }
+
+    /// the state of client `client_id`, if it has ever transacted
+    pub fn account(&self, client_id: u16) -> Option<&Account> {
+        self.accounts.state.get(&client_id)
+    }
+
+    /// counts of what happened so far, see [`Accounts::summary`]
+    pub fn summary(&self) -> Summary {
+        self.accounts.summary()
+    }
+
+    pub fn accounts(&self) -> &Accounts<S> {
+        &self.accounts
+    }
+
+    pub fn into_accounts(self) -> Accounts<S> {
+        self.accounts
+    }
+
+    /// writes every account as csv ordered by client id, see [`Accounts::serialize_to_writer`]
+    pub fn write_csv(&self, w: impl Write) -> Result<()> {
+        self.accounts.serialize_to_writer(w)
+    }
+
+    /// writes every account as csv ordered by `key`
+    pub fn write_csv_sorted(&self, w: impl Write, key: SortKey) -> Result<()> {
+        self.accounts.serialize_to_writer_sorted(w, key)
+    }
+
+    /// writes every account as an Arrow IPC file, see [`Accounts::write_arrow_ipc`]
+    #[cfg(feature = "arrow")]
+    pub fn write_arrow_ipc(&self, w: impl Write) -> Result<()> {
+        self.accounts.write_arrow_ipc(w)
+    }
+
+    /// writes the transaction history as an Arrow IPC file,
+    /// see [`Accounts::write_history_arrow_ipc`]
+    #[cfg(feature = "arrow")]
+    pub fn write_history_arrow_ipc(&self, w: impl Write) -> Result<()> {
+        self.accounts.write_history_arrow_ipc(w)
+    }
+
+    /// writes every account in `format` ordered by `key`
+    pub fn write_sorted(&self, w: impl Write, key: SortKey, format: OutputFormat) -> Result<()> {
+        match format {
+            OutputFormat::Csv => self.accounts.serialize_to_writer_sorted(w, key),
+            OutputFormat::JsonLines => self.accounts.serialize_json_lines_sorted(w, key),
+        }
+    }
+}
+
+/// One row for a worker of [`Ledger::process_csv_parallel`].
+struct Job {
+    row: Row,
+    tx: Transaction,
+    // set if the row was already rejected while reading
+    precheck: Option<TxRejection>,
+}
+
+/// passes a row rejected by a worker to `on_rejected`; fatal rejections become errors
+fn report(
+    on_rejected: &mut impl FnMut(&Row, &dyn Display) -> Result<()>,
+    (row, rejection): (Row, TxRejection),
+) -> Result<()> {
+    on_rejected(&row, &rejection)?;
+    if rejection.is_fatal() {
+        return Err(rejection.into());
+    }
+    Ok(())
+}
+
+/// applies every job sent to `shard`, sending back rejected rows.
+/// Stops after a fatal rejection or error, which is left in the shard.
+fn run_shard<S: TxStore>(
+    mut shard: Accounts<S>,
+    jobs: Receiver<Vec<Job>>,
+    rejected: Sender<(Row, TxRejection)>,
+) -> Accounts<S> {
+    for job in jobs.iter().flatten() {
+        let applied = match job.precheck {
+            // the account exists even if its first row is rejected
+            Some(rejection) => Err(shard.reject(&job.tx, rejection)),
+            None => shard.process_transaction(&job.tx),
+        };
+        if let Err(rejection) = applied {
+            let fatal = rejection.is_fatal();
+            if rejected.send((job.row, rejection)).is_err() || fatal {
+                break;
+            }
+        }
+        if shard.fatal_error.is_some() {
+            break;
+        }
+    }
+    shard
+}
@@ -0,0 +1,40 @@
+//! A toy transaction ledger.
+//!
+//! Reads deposits, withdrawals, disputes, resolves and chargebacks, and keeps
+//! the balance of every client account. [`Ledger`] is the entry point:
+//!
+//! ```
+//! use transactions::Ledger;
+//!
+//! let input = "type,client,tx,amount\ndeposit,1,1,2.5\nwithdrawal,1,2,1.0\n";
+//! let mut ledger = Ledger::new();
+//! ledger.process_csv(input.as_bytes(), |_row, _reason| Ok(()))?;
+//! assert_eq!(ledger.account(1).unwrap().avail_bal.to_string(), "1.5000");
+//!
+//! let mut out = vec![];
+//! ledger.write_csv(&mut out)?;
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+//!
+//! Lower level pieces live in [`parser`].
+
+pub mod ledger;
+pub mod parser;
+
+pub use ledger::{AsOf, Ledger};
+pub use parser::accounts::{
+    Account, Accounts, AdminPolicy, Applied, DisputePolicy, FatalError, OutputFormat, SeenTxIds,
+    SortKey, TxIdScope, TxRejection,
+};
+pub use parser::amount::{Amount, Decimal, ParseAmountError};
+pub use parser::columns::ColumnMapping;
+pub use parser::compression::Compression;
+pub use parser::journal::{Balances, Event, EventKind, Journal, JournalSink, JournalWriter};
+pub use parser::reader::{InputFormat, ParseMode, ReaderOptions, Row, RowError, TransactionReader};
+pub use parser::rebuild::{read_accounts_csv, Mismatch};
+pub use parser::report::{Quarantine, RejectionReport};
+pub use parser::snapshot::SNAPSHOT_VERSION;
+pub use parser::stats::{Stats, Summary};
+pub use parser::store::{DiskTxStore, InMemoryTxStore, TxStore};
+pub use parser::transactions::{Transaction, TransactionType};
+pub use parser::verify::Violation;
@@ -1,33 +1,1007 @@
-mod parser;
-use anyhow::{bail, Result};
-use csv::{ReaderBuilder, Trim};
-use parser::{accounts::Accounts, transactions::Transaction};
-use std::fs::File;
-use std::io::BufReader;
-use std::{env, io};
+use anyhow::{bail, Context, Result};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use encoding_rs::Encoding;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fmt::Display;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use transactions::parser::compression::{decompress, Encoder};
+use transactions::{
+    read_accounts_csv, Account, Accounts, AdminPolicy, Amount, Applied, AsOf, Balances,
+    ColumnMapping, Compression, DiskTxStore, DisputePolicy, InMemoryTxStore, InputFormat, Journal,
+    JournalWriter, Ledger, OutputFormat, ParseMode, Quarantine, ReaderOptions, RejectionReport,
+    Row, SortKey, Transaction, TransactionReader, TransactionType, TxIdScope, TxRejection, TxStore,
+};
+
+/// A toy transaction ledger.
+/// Without a subcommand, processes the inputs and writes the final state of every account to stdout.
+#[derive(Parser, Debug)]
+#[command(
+    version,
+    about,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    process: ProcessArgs,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// process the inputs and write the final state of every account to stdout
+    Process(ProcessArgs),
+    /// check that every row parses and is well formed, without applying anything.
+    /// Reports every bad row, to the error report or stdout, and fails if there were any
+    Validate(CommonArgs),
+    /// process the inputs, writing the outcome of every row and the account after it to stdout
+    Replay(ReplayArgs),
+    /// process the inputs, then show the history, state and open disputes of one client
+    Inspect(InspectArgs),
+    /// process the inputs up to a moment, then write the account of one client as it was then
+    Balance(BalanceArgs),
+    /// rebuild the accounts from an audit journal written by --journal, without the inputs,
+    /// optionally check them against a snapshot or accounts csv, and write them to stdout
+    Rebuild(RebuildArgs),
+}
+
+/// Options shared by every subcommand.
+#[derive(Args, Debug)]
+struct CommonArgs {
+    /// files of transactions, processed in order into the same accounts. `-` reads stdin.
+    /// Inputs compressed with gzip or zstd are decompressed as they are read
+    #[arg(required = true)]
+    inputs: Vec<PathBuf>,
+    /// format of the inputs: csv or jsonl.
+    /// By default chosen by extension: .jsonl or .ndjson for JSON Lines, anything else csv
+    #[arg(long, value_name = "FORMAT")]
+    input_format: Option<InputFormat>,
+    /// read the csv column mapping from this JSON file,
+    /// e.g. {"headers": {"tx": "id"}} or {"positions": ["client", "tx", "type", "amount"]}
+    #[arg(long, value_name = "PATH")]
+    columns_config: Option<PathBuf>,
+    /// expect the csv column of FIELD under HEADER instead of its own name. Repeatable
+    #[arg(long, value_name = "FIELD=HEADER")]
+    column: Vec<String>,
+    /// the csv inputs have no header row; the field in each column, in order, e.g.
+    /// client,tx,type,amount. Names that are not a field skip the column
+    #[arg(long, value_name = "FIELDS", value_delimiter = ',')]
+    headerless: Option<Vec<String>>,
+    /// csv field delimiter: a single character, or `tab`.
+    /// By default the most common of `,` `;` tab `|` in the first line
+    #[arg(long, value_name = "CHAR", value_parser = parse_char)]
+    delimiter: Option<u8>,
+    /// csv quote character
+    #[arg(long, value_name = "CHAR", value_parser = parse_char, default_value = "\"")]
+    quote: u8,
+    /// skip csv lines starting with this character
+    #[arg(long, value_name = "CHAR", value_parser = parse_char)]
+    comment: Option<u8>,
+    /// character encoding of the inputs, e.g. latin1 or utf-16le. By default UTF-8
+    #[arg(long, value_name = "LABEL", value_parser = parse_encoding)]
+    encoding: Option<&'static Encoding>,
+    /// keep a byte order mark at the start of an input instead of dropping it
+    #[arg(long)]
+    keep_bom: bool,
+    #[command(flatten)]
+    output: OutputArgs,
+    /// write one csv row per rejected or malformed input record to this file
+    #[arg(long, value_name = "PATH")]
+    rejections: Option<PathBuf>,
+    /// what to do with malformed rows: stop (strict), skip them (lenient),
+    /// or skip them and copy them to the --quarantine directory (quarantine)
+    #[arg(long, value_enum, value_name = "MODE", default_value = "strict")]
+    parse_mode: ParseModeArg,
+    /// with --parse-mode quarantine, copy the malformed rows of each input as they were read,
+    /// after its header, to a file of this directory named like the input (stdin for -)
+    #[arg(long, value_name = "DIR", required_if_eq("parse_mode", "quarantine"))]
+    quarantine: Option<PathBuf>,
+}
+
+/// Where and how the output is written.
+#[derive(Args, Debug)]
+struct OutputArgs {
+    /// write the output to this file instead of stdout
+    #[arg(long, short, value_name = "PATH")]
+    output: Option<PathBuf>,
+    /// format of the output: csv or jsonl. By default chosen by the extension of --output, or csv
+    #[arg(long, value_name = "FORMAT")]
+    output_format: Option<OutputFormat>,
+    /// compress the output: none, gzip or zstd.
+    /// By default gzip for a --output ending in .gz, zstd for .zst, otherwise none
+    #[arg(long, value_name = "COMPRESSION")]
+    compress: Option<Compression>,
+}
+
+/// Options of every subcommand that applies transactions.
+#[derive(Args, Debug)]
+struct LedgerArgs {
+    /// keep the transaction history in an on-disk store at this path instead of in memory.
+    /// Bounds memory use for large inputs. Any existing file at the path is overwritten.
+    #[arg(long, value_name = "PATH")]
+    tx_store: Option<PathBuf>,
+    /// start from the state saved in this snapshot instead of from empty accounts
+    #[arg(long, value_name = "PATH")]
+    load_snapshot: Option<PathBuf>,
+    /// which transactions may be disputed: deposits-only or deposits-and-withdrawals
+    #[arg(
+        long,
+        value_name = "POLICY",
+        default_value = "deposits-and-withdrawals"
+    )]
+    dispute_policy: DisputePolicy,
+    /// whether tx ids are unique across all clients (global) or only within a client (per-client)
+    #[arg(long, value_name = "SCOPE", default_value = "global")]
+    tx_id_scope: TxIdScope,
+    /// which inputs admin operations (adjust, lock, unlock, close) are accepted from:
+    /// anywhere, or admin-input-only to reject them outside --admin-input
+    #[arg(long, value_name = "POLICY", default_value = "anywhere")]
+    admin_policy: AdminPolicy,
+    /// after processing, check the invariants of every account and the conservation of money;
+    /// fail listing every violation
+    #[arg(long)]
+    verify: bool,
+    /// debug mode: check the invariants of an account after every transaction applied to it,
+    /// aborting at the first violation. Slow
+    #[arg(long)]
+    verify_each: bool,
+    /// append every change to an account to this audit journal, as JSON Lines
+    #[arg(long, value_name = "PATH")]
+    journal: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+struct ProcessArgs {
+    #[command(flatten)]
+    common: CommonArgs,
+    #[command(flatten)]
+    ledger: LedgerArgs,
+    /// after processing, save the complete state to this snapshot file
+    #[arg(long, value_name = "PATH")]
+    save_snapshot: Option<PathBuf>,
+    /// a file of admin operations, applied after the inputs, in order, on one thread.
+    /// Read like the inputs. Repeatable
+    #[arg(long, value_name = "PATH")]
+    admin_input: Vec<PathBuf>,
+    /// number of worker threads; clients are split across workers by client id.
+    /// With --tx-store, every worker keeps a scratch store at PATH.N while processing
+    #[arg(long, value_name = "N", default_value_t = 1)]
+    jobs: usize,
+    /// also write the accounts to this Arrow IPC file, with amounts as decimal128 (scale 4)
+    #[cfg(feature = "arrow")]
+    #[arg(long, value_name = "PATH")]
+    export_accounts: Option<PathBuf>,
+    /// also write the transaction history to this Arrow IPC file
+    #[cfg(feature = "arrow")]
+    #[arg(long, value_name = "PATH")]
+    export_history: Option<PathBuf>,
+    /// order of the output rows: client, total, available or locked-first
+    #[arg(long, value_name = "KEY", default_value = "client")]
+    sort: SortKey,
+    /// after processing, print counts of rows, transactions by outcome and the money moved
+    /// to stderr, as text or json
+    #[arg(long, value_enum, value_name = "FORMAT")]
+    summary: Option<SummaryFormat>,
+}
+
+#[derive(Args, Debug)]
+struct ReplayArgs {
+    #[command(flatten)]
+    common: CommonArgs,
+    #[command(flatten)]
+    ledger: LedgerArgs,
+}
+
+#[derive(Args, Debug)]
+struct InspectArgs {
+    #[command(flatten)]
+    common: CommonArgs,
+    #[command(flatten)]
+    ledger: LedgerArgs,
+    /// the client to inspect
+    #[arg(long)]
+    client: u16,
+}
+
+#[derive(Args, Debug)]
+struct BalanceArgs {
+    #[command(flatten)]
+    common: CommonArgs,
+    #[command(flatten)]
+    ledger: LedgerArgs,
+    /// the client whose account to write
+    #[arg(long)]
+    client: u16,
+    /// the moment: tx:<id> for right after the first row with that tx id,
+    /// or row:<n> for right after the n-th row of the inputs
+    #[arg(long, value_name = "MOMENT")]
+    as_of: AsOf,
+}
+
+#[derive(Args, Debug)]
+struct RebuildArgs {
+    /// the audit journal. `-` reads stdin. A gzip or zstd journal is decompressed as it is read
+    journal: PathBuf,
+    #[command(flatten)]
+    output: OutputArgs,
+    /// keep the rebuilt transaction history in an on-disk store at this path instead of in memory.
+    /// Any existing file at the path is overwritten
+    #[arg(long, value_name = "PATH")]
+    tx_store: Option<PathBuf>,
+    /// whether tx ids are unique across all clients (global) or only within a client (per-client),
+    /// as when the journal was written
+    #[arg(long, value_name = "SCOPE", default_value = "global")]
+    tx_id_scope: TxIdScope,
+    /// fail unless the rebuilt state matches this snapshot: balances, open disputes,
+    /// chargebacks, transaction history and used up tx ids
+    #[arg(long, value_name = "PATH")]
+    check_snapshot: Option<PathBuf>,
+    /// fail unless the rebuilt balances match this accounts csv, as written by process
+    #[arg(long, value_name = "PATH")]
+    check_accounts: Option<PathBuf>,
+    /// save the rebuilt state to this snapshot file
+    #[arg(long, value_name = "PATH")]
+    save_snapshot: Option<PathBuf>,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum ParseModeArg {
+    Strict,
+    Lenient,
+    Quarantine,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum SummaryFormat {
+    Text,
+    Json,
+}
+
+impl From<ParseModeArg> for ParseMode {
+    fn from(mode: ParseModeArg) -> Self {
+        match mode {
+            ParseModeArg::Strict => ParseMode::Strict,
+            ParseModeArg::Lenient => ParseMode::Lenient,
+            ParseModeArg::Quarantine => ParseMode::Quarantine,
+        }
+    }
+}
+
+/// Where rejected and malformed rows go, and how many there were in the current input.
+struct BadRows {
+    report: Option<RejectionReport<Box<dyn Write>>>,
+    /// the --quarantine directory, in quarantine mode
+    quarantine: Option<PathBuf>,
+    /// the quarantine files given to inputs so far
+    quarantined: HashSet<PathBuf>,
+    rejected: u64,
+    malformed: u64,
+}
+
+impl BadRows {
+    /// writes the rejection report to `report`, if any
+    fn new(args: &CommonArgs, report: Option<Box<dyn Write>>) -> Result<Self> {
+        let quarantine = match &args.quarantine {
+            Some(dir) if args.parse_mode == ParseModeArg::Quarantine => {
+                fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+                Some(dir.clone())
+            }
+            _ => None,
+        };
+        Ok(BadRows {
+            report: report.map(RejectionReport::new),
+            quarantine,
+            quarantined: HashSet::new(),
+            rejected: 0,
+            malformed: 0,
+        })
+    }
+
+    /// writes the rejection report to the --rejections file, if any
+    fn from_args(args: &CommonArgs) -> Result<Self> {
+        let report = match &args.rejections {
+            Some(path) => Some(Box::new(File::create(path)?) as Box<dyn Write>),
+            None => None,
+        };
+        BadRows::new(args, report)
+    }
+
+    /// opens the input at `path` like `open_input`, quarantining its malformed rows
+    /// in a file of the --quarantine directory named like it, created on the first one
+    fn open(&mut self, args: &CommonArgs, path: &Path) -> Result<TransactionReader<Box<dyn Read>>> {
+        let reader = open_input(args, path)?;
+        let Some(dir) = &self.quarantine else {
+            return Ok(reader);
+        };
+        let name = if path.as_os_str() == "-" {
+            Path::new("stdin")
+        } else {
+            Compression::strip_extension(path)
+        };
+        let Some(name) = name.file_name() else {
+            bail!("{} has no file name to quarantine to", input_name(path));
+        };
+        let file = dir.join(name);
+        if !self.quarantined.insert(file.clone()) {
+            bail!(
+                "{} would be quarantined to {}, like an earlier input",
+                input_name(path),
+                file.display()
+            );
+        }
+        Ok(reader.with_quarantine(Quarantine::new(LazyFile {
+            path: file,
+            file: None,
+        })))
+    }
+
+    /// starts counting the rows of input `name`
+    fn start_input(&mut self, name: &str) {
+        if let Some(report) = self.report.as_mut() {
+            report.set_input(name);
+        }
+        self.rejected = 0;
+        self.malformed = 0;
+    }
+
+    fn record(&mut self, row: &Row, reason: &dyn Display) -> Result<()> {
+        if row.transaction.is_err() {
+            self.malformed += 1;
+        } else {
+            self.rejected += 1;
+        }
+        match self.report.as_mut() {
+            Some(report) => report.record(row, reason),
+            None => Ok(()),
+        }
+    }
+
+    /// prints a summary of the current input to stderr
+    fn summarize(&self, name: &str, rows: u64) {
+        eprintln!(
+            "{}: {} rows, {} applied, {} rejected, {} malformed",
+            name,
+            rows,
+            rows - self.rejected - self.malformed,
+            self.rejected,
+            self.malformed
+        );
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if let Some(report) = self.report.as_mut() {
+            report.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// A file created on the first write, so inputs without malformed rows leave no quarantine.
+struct LazyFile {
+    path: PathBuf,
+    file: Option<File>,
+}
+
+impl Write for LazyFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => self.file.insert(File::create(&self.path)?),
+        };
+        file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.file {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+/// One step of `replay` or of the history shown by `inspect`:
+/// a row of the input, its outcome, and the account after it.
+#[derive(Serialize, Debug)]
+struct Step {
+    input: String,
+    line: u64,
+    #[serde(rename = "type")]
+    transaction_type: TransactionType,
+    client: u16,
+    tx: u32,
+    amount: Option<Amount>,
+    /// `applied`, or the reason the row was rejected
+    outcome: String,
+    available: Amount,
+    held: Amount,
+    total: Amount,
+    locked: bool,
+}
+
+/// Everything `inspect` shows about a client.
+#[derive(Serialize, Debug)]
+struct Inspection<'a> {
+    history: Vec<Step>,
+    account: &'a Account,
+    open_disputes: Vec<OpenDispute>,
+}
+
+/// An open dispute, as shown by `inspect`.
+#[derive(Serialize, Debug)]
+struct OpenDispute {
+    client: u16,
+    tx: u32,
+    #[serde(rename = "type")]
+    transaction_type: TransactionType,
+    amount: Option<Amount>,
+    /// the part of the amount held by the dispute
+    held: Amount,
+}
 
 fn main() -> Result<()> {
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        bail!("Wrong number of arguments. Expected exactly one. Usage: `./transactions input.csv`");
+    let cli = Cli::parse();
+    match cli.command.unwrap_or(Command::Process(cli.process)) {
+        Command::Process(args) => process(&args),
+        Command::Validate(args) => validate(&args),
+        Command::Replay(args) => match &args.ledger.tx_store {
+            Some(path) => replay(&args, DiskTxStore::create(path)?),
+            None => replay(&args, InMemoryTxStore::new()),
+        },
+        Command::Inspect(args) => match &args.ledger.tx_store {
+            Some(path) => inspect(&args, DiskTxStore::create(path)?),
+            None => inspect(&args, InMemoryTxStore::new()),
+        },
+        Command::Balance(args) => match &args.ledger.tx_store {
+            Some(path) => balance(&args, DiskTxStore::create(path)?),
+            None => balance(&args, InMemoryTxStore::new()),
+        },
+        Command::Rebuild(args) => match &args.tx_store {
+            Some(path) => rebuild(&args, DiskTxStore::create(path)?),
+            None => rebuild(&args, InMemoryTxStore::new()),
+        },
+    }
+}
+
+fn process(args: &ProcessArgs) -> Result<()> {
+    match &args.ledger.tx_store {
+        Some(path) => {
+            let result = run_process(args, DiskTxStore::create(path)?, |worker| {
+                DiskTxStore::create(shard_path(path, worker))
+            });
+            for worker in 0..args.jobs {
+                let _ = fs::remove_file(shard_path(path, worker));
+            }
+            result
+        }
+        None => run_process(args, InMemoryTxStore::new(), |_| Ok(InMemoryTxStore::new())),
+    }
+}
+
+/// processes the inputs into a ledger backed by `store` and prints the result,
+/// and, with more than one job, worker stores from `new_store`
+fn run_process<S: TxStore + Send>(
+    args: &ProcessArgs,
+    store: S,
+    new_store: impl FnMut(usize) -> Result<S>,
+) -> Result<()> {
+    let mut ledger = new_ledger(&args.common, &args.ledger, store)?;
+    let mut bad_rows = BadRows::from_args(&args.common)?;
+    let result = process_inputs(args, &mut ledger, &mut bad_rows, new_store);
+    bad_rows.flush()?;
+    flush_journal(&ledger)?;
+    result?;
+    check_invariants(&args.ledger, &ledger)?;
+    if let Some(path) = &args.save_snapshot {
+        ledger.save_snapshot(BufWriter::new(File::create(path)?))?;
+    }
+    #[cfg(feature = "arrow")]
+    {
+        if let Some(path) = &args.export_accounts {
+            ledger.write_arrow_ipc(BufWriter::new(File::create(path)?))?;
+        }
+        if let Some(path) = &args.export_history {
+            ledger.write_history_arrow_ipc(BufWriter::new(File::create(path)?))?;
+        }
+    }
+    match args.summary {
+        Some(SummaryFormat::Text) => eprintln!("{}", ledger.summary()),
+        Some(SummaryFormat::Json) => eprintln!("{}", serde_json::to_string(&ledger.summary())?),
+        None => {}
+    }
+    let (mut out, format) = open_output(&args.common.output)?;
+    ledger.write_sorted(&mut out, args.sort, format)?;
+    out.finish()?;
+
+    Ok(())
+}
+
+/// processes every input in order, printing a summary of each to stderr
+fn process_inputs<S: TxStore + Send>(
+    args: &ProcessArgs,
+    ledger: &mut Ledger<S>,
+    bad_rows: &mut BadRows,
+    mut new_store: impl FnMut(usize) -> Result<S>,
+) -> Result<()> {
+    for path in &args.common.inputs {
+        let name = input_name(path);
+        let reader = bad_rows.open(&args.common, path)?;
+        bad_rows.start_input(&name);
+        // rejected transactions are ignored per spec, but reported if asked to
+        let on_rejected = |row: &Row, reason: &dyn Display| bad_rows.record(row, reason);
+        let rows = if args.jobs > 1 {
+            ledger.process_reader_parallel(reader, args.jobs, &mut new_store, on_rejected)
+        } else {
+            ledger.process_reader(reader, on_rejected)
+        }
+        .with_context(|| format!("processing {}", name))?;
+        bad_rows.summarize(&name, rows);
+    }
+    for path in &args.admin_input {
+        let name = input_name(path);
+        let reader = bad_rows.open(&args.common, path)?;
+        bad_rows.start_input(&name);
+        let on_rejected = |row: &Row, reason: &dyn Display| bad_rows.record(row, reason);
+        let rows = ledger
+            .process_admin_reader(reader, on_rejected)
+            .with_context(|| format!("processing {}", name))?;
+        bad_rows.summarize(&name, rows);
+    }
+    Ok(())
+}
+
+/// reports every row of the inputs that does not parse or fails `Transaction::check_state`.
+/// The report is csv, written to --rejections or else the output.
+fn validate(args: &CommonArgs) -> Result<()> {
+    let out: Box<dyn Write> = match &args.rejections {
+        Some(path) => Box::new(File::create(path)?),
+        None => Box::new(open_output(&args.output)?.0),
+    };
+    let mut bad_rows = BadRows::new(args, Some(out))?;
+    let mut total_bad = 0;
+    for path in &args.inputs {
+        let name = input_name(path);
+        bad_rows.start_input(&name);
+        let mut rows = 0;
+        let mut reader = bad_rows.open(args, path)?;
+        while let Some(row) = reader
+            .next_row()
+            .with_context(|| format!("reading {}", name))?
+        {
+            rows += 1;
+            if let Err(err) = &row.transaction {
+                bad_rows.record(&row, err)?;
+                if args.parse_mode == ParseModeArg::Quarantine {
+                    reader.quarantine(&row)?;
+                }
+            }
+        }
+        eprintln!("{}: {} rows, {} bad", name, rows, bad_rows.malformed);
+        total_bad += bad_rows.malformed;
+    }
+    bad_rows.flush()?;
+    if total_bad > 0 {
+        bail!("{} bad rows", total_bad);
+    }
+    Ok(())
+}
+
+/// processes the inputs, writing every step to the output
+fn replay<S: TxStore>(args: &ReplayArgs, store: S) -> Result<()> {
+    let mut ledger = new_ledger(&args.common, &args.ledger, store)?;
+    let mut bad_rows = BadRows::from_args(&args.common)?;
+    let (mut out, format) = open_output(&args.common.output)?;
+    let result = match format {
+        OutputFormat::Csv => {
+            let mut wtr = csv::Writer::from_writer(&mut out);
+            let result = trace_inputs(&args.common, &mut ledger, &mut bad_rows, |step| {
+                wtr.serialize(step)?;
+                Ok(())
+            });
+            wtr.flush()?;
+            result
+        }
+        OutputFormat::JsonLines => trace_inputs(&args.common, &mut ledger, &mut bad_rows, |step| {
+            write_json_line(&mut out, &step)
+        }),
+    };
+    out.finish()?;
+    bad_rows.flush()?;
+    flush_journal(&ledger)?;
+    result?;
+    check_invariants(&args.ledger, &ledger)
+}
+
+/// processes the inputs, then writes the history of the client, its account and its open disputes
+/// to the output: in csv as three tables separated by blank lines, in JSON Lines as one object
+fn inspect<S: TxStore>(args: &InspectArgs, store: S) -> Result<()> {
+    let mut ledger = new_ledger(&args.common, &args.ledger, store)?;
+    let mut bad_rows = BadRows::from_args(&args.common)?;
+    let mut history = Vec::new();
+    let result = trace_inputs(&args.common, &mut ledger, &mut bad_rows, |step| {
+        if step.client == args.client {
+            history.push(step);
+        }
+        Ok(())
+    });
+    bad_rows.flush()?;
+    flush_journal(&ledger)?;
+    result?;
+    check_invariants(&args.ledger, &ledger)?;
+    let account = match ledger.account(args.client) {
+        Some(account) => account,
+        None => bail!("client {} has no account", args.client),
+    };
+    let mut disputes: Vec<(u32, Amount)> = account
+        .disputes
+        .iter()
+        .map(|(tx_id, held)| (*tx_id, *held))
+        .collect();
+    disputes.sort_unstable();
+    let mut open_disputes = Vec::with_capacity(disputes.len());
+    for (tx_id, held) in disputes {
+        if let Some(tx) = ledger.accounts().store.get(args.client, tx_id)? {
+            open_disputes.push(OpenDispute {
+                client: tx.client_id,
+                tx: tx.tx_id,
+                transaction_type: tx.transaction_type,
+                amount: tx.amount,
+                held,
+            });
+        }
+    }
+
+    let (mut out, format) = open_output(&args.common.output)?;
+    match format {
+        OutputFormat::Csv => {
+            write_csv_table(&mut out, &history)?;
+            writeln!(out)?;
+            write_csv_table(&mut out, std::slice::from_ref(account))?;
+            writeln!(out)?;
+            write_csv_table(&mut out, &open_disputes)?;
+        }
+        OutputFormat::JsonLines => write_json_line(
+            &mut out,
+            &Inspection {
+                history,
+                account,
+                open_disputes,
+            },
+        )?,
+    }
+    out.finish()?;
+    Ok(())
+}
+
+/// applies every row of the inputs one at a time, calling `on_step` with the outcome of each.
+/// Rejected and malformed rows are also written to `bad_rows`;
+/// like `Ledger::process_reader`, a malformed row in strict mode or a failing tx store
+/// stops processing.
+fn trace_inputs<S: TxStore>(
+    args: &CommonArgs,
+    ledger: &mut Ledger<S>,
+    bad_rows: &mut BadRows,
+    mut on_step: impl FnMut(Step) -> Result<()>,
+) -> Result<()> {
+    for path in &args.inputs {
+        let name = input_name(path);
+        bad_rows.start_input(&name);
+        let mut rows = 0;
+        let mut reader = bad_rows.open(args, path)?;
+        while let Some(row) = reader
+            .next_row()
+            .with_context(|| format!("reading {}", name))?
+        {
+            rows += 1;
+            let tx = match &row.transaction {
+                Ok(tx) => *tx,
+                Err(err) => {
+                    bad_rows.record(&row, err)?;
+                    match ParseMode::from(args.parse_mode) {
+                        ParseMode::Strict => {
+                            bail!("processing {}: line {}: {}", name, row.line(), err)
+                        }
+                        ParseMode::Lenient => continue,
+                        ParseMode::Quarantine => {
+                            reader.quarantine(&row)?;
+                            continue;
+                        }
+                    }
+                }
+            };
+            let outcome = ledger.process(&tx);
+            if let Err(rejection) = &outcome {
+                bad_rows.record(&row, rejection)?;
+                if rejection.is_fatal() {
+                    return Err(rejection.clone().into());
+                }
+            }
+            if let Some(fatal_error) = ledger.take_fatal_error() {
+                return Err(fatal_error.into());
+            }
+            // every processed row creates the account if needed,
+            // except an admin operation on a client without one
+            let balances = ledger
+                .account(tx.client_id)
+                .map(Balances::of)
+                .unwrap_or_default();
+            on_step(step(&name, &row, &tx, &outcome, balances))?;
+        }
+        bad_rows.summarize(&name, rows);
+    }
+    Ok(())
+}
+
+fn step(
+    input: &str,
+    row: &Row,
+    tx: &Transaction,
+    outcome: &Result<Applied, TxRejection>,
+    balances: Balances,
+) -> Step {
+    Step {
+        input: input.to_string(),
+        line: row.line(),
+        transaction_type: tx.transaction_type,
+        client: tx.client_id,
+        tx: tx.tx_id,
+        amount: tx.amount,
+        outcome: match outcome {
+            Ok(_) => "applied".to_string(),
+            Err(rejection) => rejection.to_string(),
+        },
+        available: balances.available,
+        held: balances.held,
+        total: balances.total,
+        locked: balances.locked,
+    }
+}
+
+fn write_json_line<T: Serialize>(w: &mut impl Write, value: &T) -> Result<()> {
+    serde_json::to_writer(&mut *w, value)?;
+    w.write_all(b"\n")?;
+    Ok(())
+}
+
+fn write_csv_table<T: Serialize>(w: &mut impl Write, rows: &[T]) -> Result<()> {
+    let mut wtr = csv::Writer::from_writer(w);
+    for row in rows {
+        wtr.serialize(row)?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+/// processes the inputs until `--as-of`, then writes the account of the client as it was then
+fn balance<S: TxStore>(args: &BalanceArgs, store: S) -> Result<()> {
+    let mut ledger = new_ledger(&args.common, &args.ledger, store)?;
+    let mut bad_rows = BadRows::from_args(&args.common)?;
+    let result = process_until(args, &mut ledger, &mut bad_rows);
+    bad_rows.flush()?;
+    flush_journal(&ledger)?;
+    if !result? {
+        match args.as_of {
+            AsOf::Tx(tx_id) => bail!("no row of the inputs has tx {}", tx_id),
+            AsOf::Row(_) => bail!("the inputs have only {} rows", ledger.summary().rows_read),
+        }
+    }
+    check_invariants(&args.ledger, &ledger)?;
+    let account = match ledger.account(args.client) {
+        Some(account) => account,
+        None => bail!("client {} had no account yet", args.client),
+    };
+    let (mut out, format) = open_output(&args.common.output)?;
+    match format {
+        OutputFormat::Csv => write_csv_table(&mut out, std::slice::from_ref(account))?,
+        OutputFormat::JsonLines => write_json_line(&mut out, account)?,
+    }
+    out.finish()?;
+    Ok(())
+}
+
+/// processes the inputs in order until `--as-of`, returning whether it was reached
+fn process_until<S: TxStore>(
+    args: &BalanceArgs,
+    ledger: &mut Ledger<S>,
+    bad_rows: &mut BadRows,
+) -> Result<bool> {
+    for path in &args.common.inputs {
+        let name = input_name(path);
+        let reader = bad_rows.open(&args.common, path)?;
+        bad_rows.start_input(&name);
+        let on_rejected = |row: &Row, reason: &dyn Display| bad_rows.record(row, reason);
+        let (rows, reached) = ledger
+            .process_reader_until(reader, args.as_of, on_rejected)
+            .with_context(|| format!("processing {}", name))?;
+        bad_rows.summarize(&name, rows);
+        if reached {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// rebuilds the accounts from the journal, checks them against the expected state if given,
+/// and writes them to the output. Every mismatch is printed to stderr
+fn rebuild<S: TxStore>(args: &RebuildArgs, store: S) -> Result<()> {
+    let name = input_name(&args.journal);
+    let journal: Box<dyn Read> = if args.journal.as_os_str() == "-" {
+        Box::new(io::stdin().lock())
+    } else {
+        Box::new(File::open(&args.journal).with_context(|| format!("opening {}", name))?)
+    };
+    let accounts = Accounts::with_store(store)
+        .with_tx_id_scope(args.tx_id_scope)
+        .replay_journal(BufReader::new(decompress(journal)?))
+        .with_context(|| format!("replaying {}", name))?;
+
+    let mut mismatches = vec![];
+    if let Some(path) = &args.check_snapshot {
+        let expected = Accounts::new()
+            .with_tx_id_scope(args.tx_id_scope)
+            .load_snapshot(BufReader::new(File::open(path)?))
+            .with_context(|| format!("loading {}", path.display()))?;
+        mismatches.extend(accounts.compare(&expected)?);
+    }
+    if let Some(path) = &args.check_accounts {
+        let expected = read_accounts_csv(decompress(File::open(path)?)?)
+            .with_context(|| format!("reading {}", path.display()))?;
+        mismatches.extend(accounts.compare_balances(&expected));
+    }
+    for mismatch in &mismatches {
+        eprintln!("mismatch: {}", mismatch);
+    }
+    if !mismatches.is_empty() {
+        bail!("{} mismatches with the journal", mismatches.len());
+    }
+
+    let ledger = Ledger::from_accounts(accounts);
+    if let Some(path) = &args.save_snapshot {
+        ledger.save_snapshot(BufWriter::new(File::create(path)?))?;
+    }
+    let (mut out, format) = open_output(&args.output)?;
+    ledger.write_sorted(&mut out, SortKey::ClientId, format)?;
+    out.finish()?;
+    Ok(())
+}
+
+/// creates a ledger backed by `store` as configured by `args`
+fn new_ledger<S: TxStore>(common: &CommonArgs, args: &LedgerArgs, store: S) -> Result<Ledger<S>> {
+    let mut ledger = Ledger::with_store(store)
+        .with_parse_mode(common.parse_mode.into())
+        .with_dispute_policy(args.dispute_policy.clone())
+        .with_tx_id_scope(args.tx_id_scope)
+        .with_admin_policy(args.admin_policy)
+        .with_verify_each(args.verify_each);
+    if let Some(path) = &args.load_snapshot {
+        ledger = ledger
+            .load_snapshot(BufReader::new(File::open(path)?))
+            .with_context(|| format!("loading {}", path.display()))?;
     }
-    let f = File::open(&args[1])?;
+    if let Some(path) = &args.journal {
+        let file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(path)
+            .with_context(|| format!("opening journal {}", path.display()))?;
+        ledger = ledger.with_journal(Journal::new(JournalWriter::new(BufWriter::new(file))));
+    }
+    Ok(ledger)
+}
 
-    let mut rdr = ReaderBuilder::new()
-        .trim(Trim::All)
-        .delimiter(b',')
-        .flexible(true)
-        .from_reader(BufReader::new(f));
-    let mut accounts = Accounts::new();
-    let mut raw_record = csv::ByteRecord::new();
-    let headers = rdr.byte_headers()?.clone();
-    // if there is an error deserializing, fail.
-    while rdr.read_byte_record(&mut raw_record)? {
-        let tx: Transaction = raw_record.deserialize(Some(&headers))?;
-        assert!(tx.check_state());
-        accounts.process_transaction(&tx);
+/// writes out the buffered end of the journal of `ledger`, if it keeps one
+fn flush_journal<S: TxStore>(ledger: &Ledger<S>) -> Result<()> {
+    match &ledger.accounts().journal {
+        Some(journal) => journal.flush().context("writing journal"),
+        None => Ok(()),
     }
-    accounts.serialize_to_writer(io::stdout())?;
+}
 
+/// with --verify, fails if the ledger breaks an invariant, printing every violation to stderr
+fn check_invariants<S: TxStore>(args: &LedgerArgs, ledger: &Ledger<S>) -> Result<()> {
+    if !args.verify {
+        return Ok(());
+    }
+    let violations = ledger.verify()?;
+    for violation in &violations {
+        eprintln!("invariant violated: {}", violation);
+    }
+    if !violations.is_empty() {
+        bail!("{} invariant violations", violations.len());
+    }
     Ok(())
 }
+
+/// opens the input at `path`, or stdin for `-`, in the format given by `args` or its extension
+fn open_input(args: &CommonArgs, path: &Path) -> Result<TransactionReader<Box<dyn Read>>> {
+    let format = args
+        .input_format
+        .or_else(|| InputFormat::from_path(path))
+        .unwrap_or_default();
+    let input: Box<dyn Read> = if path.as_os_str() == "-" {
+        Box::new(io::stdin().lock())
+    } else {
+        Box::new(File::open(path).with_context(|| format!("opening {}", input_name(path)))?)
+    };
+    let input = decompress(input).with_context(|| format!("reading {}", input_name(path)))?;
+    let options = ReaderOptions {
+        format,
+        columns: column_mapping(args)?,
+        delimiter: args.delimiter,
+        quote: args.quote,
+        comment: args.comment,
+        encoding: args.encoding,
+        strip_bom: !args.keep_bom,
+    };
+    TransactionReader::with_options(input, &options)
+        .with_context(|| format!("reading {}", input_name(path)))
+}
+
+/// the --columns-config mapping, with --column and --headerless applied over it
+fn column_mapping(args: &CommonArgs) -> Result<ColumnMapping> {
+    let mut mapping = match &args.columns_config {
+        Some(path) => ColumnMapping::from_json(BufReader::new(File::open(path)?))
+            .with_context(|| format!("reading {}", path.display()))?,
+        None => ColumnMapping::default(),
+    };
+    for column in &args.column {
+        let Some((field, header)) = column.split_once('=') else {
+            bail!("--column {:?} is not FIELD=HEADER", column);
+        };
+        mapping.rename(field, header)?;
+    }
+    if let Some(positions) = &args.headerless {
+        mapping.positions = Some(positions.clone());
+    }
+    Ok(mapping)
+}
+
+/// parses a single ASCII character, or `tab`
+fn parse_char(s: &str) -> Result<u8> {
+    match s.as_bytes() {
+        [c] if c.is_ascii() => Ok(*c),
+        _ if s == "tab" || s == "\\t" => Ok(b'\t'),
+        _ => bail!("expected a single ASCII character or tab, got {:?}", s),
+    }
+}
+
+/// finds the encoding with the WHATWG label `s`. Note that latin1 and iso-8859-1 mean windows-1252
+fn parse_encoding(s: &str) -> Result<&'static Encoding> {
+    match Encoding::for_label(s.as_bytes()) {
+        Some(encoding) => Ok(encoding),
+        None => bail!("unknown encoding {:?}", s),
+    }
+}
+
+/// opens the --output file, or stdout, and picks its format and compression from `args`
+/// or its extension
+fn open_output(args: &OutputArgs) -> Result<(Encoder<Box<dyn Write>>, OutputFormat)> {
+    let format = args
+        .output_format
+        .or_else(|| args.output.as_deref().and_then(OutputFormat::from_path))
+        .unwrap_or_default();
+    let compression = args
+        .compress
+        .or_else(|| args.output.as_deref().and_then(Compression::from_path))
+        .unwrap_or_default();
+    let out: Box<dyn Write> = match &args.output {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(io::stdout().lock()),
+    };
+    Ok((compression.encoder(out)?, format))
+}
+
+fn input_name(path: &Path) -> String {
+    path.display().to_string()
+}
+
+/// path of the scratch tx store of worker `worker`
+fn shard_path(path: &Path, worker: usize) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(format!(".{}", worker));
+    path.into()
+}
@@ -1,208 +1,1068 @@
-#![allow(clippy::unnecessary_unwrap)]
-use crate::parser::transactions::{
-    deserialize_floating_point, FloatingPoint, Transaction, TransactionType,
-};
-use anyhow::Result;
+use crate::parser::amount::Amount;
+use crate::parser::compression::Compression;
+use crate::parser::journal::{self, Balances, Journal};
+use crate::parser::stats::{Stats, Summary};
+use crate::parser::store::{InMemoryTxStore, TxStore};
+use crate::parser::transactions::{Transaction, TransactionType};
+use anyhow::{bail, Result};
 use csv::WriterBuilder;
-use serde::{self, Deserialize, Serialize, Serializer};
-use std::collections::{HashMap, HashSet};
+use serde::{self, Deserialize, Serialize};
+use std::collections::{hash_map::Entry, HashMap, HashSet};
+use std::fmt;
 use std::io::Write;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use thiserror::Error;
+
+/// The effect of a transaction that was applied to an account.
+/// Each variant of a client transaction carries the (unsigned) amount of money that moved;
+/// for a partial dispute, resolve or chargeback, only that part of the disputed transaction.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Applied {
+    Deposit(Amount),
+    Withdrawal(Amount),
+    Dispute(Amount),
+    Resolve(Amount),
+    Chargeback(Amount),
+    /// the signed amount added to the available and total balances
+    Adjustment(Amount),
+    Lock,
+    Unlock,
+    Close,
+}
+
+/// The reason a transaction was ignored by `Accounts::process_transaction`.
+/// One variant per point of failure.
+#[derive(Error, Debug, Clone, Eq, PartialEq)]
+pub enum TxRejection {
+    #[error("client {client_id} is locked; tx {tx_id} ignored")]
+    AccountLocked { client_id: u16, tx_id: u32 },
+    #[error("client {client_id} is closed; tx {tx_id} ignored")]
+    AccountClosed { client_id: u16, tx_id: u32 },
+    #[error("client {client_id} has no account; admin tx {tx_id} ignored")]
+    UnknownClient { client_id: u16, tx_id: u32 },
+    #[error("client {client_id} is not locked; unlock tx {tx_id} ignored")]
+    NotLocked { client_id: u16, tx_id: u32 },
+    #[error("client {client_id} still holds funds or open disputes; close tx {tx_id} ignored")]
+    NotEmpty { client_id: u16, tx_id: u32 },
+    #[error("tx {tx_id} of client {client_id} is an admin operation outside the admin input")]
+    AdminOnly { client_id: u16, tx_id: u32 },
+    #[error("tx {tx_id} would overdraw the available balance of client {client_id}")]
+    InsufficientFunds { client_id: u16, tx_id: u32 },
+    #[error("tx {tx_id} would overflow a balance of client {client_id}")]
+    Overflow { client_id: u16, tx_id: u32 },
+    #[error("tx {tx_id} of client {client_id} has no amount")]
+    MissingAmount { client_id: u16, tx_id: u32 },
+    #[error("tx {tx_id} of client {client_id} has a negative amount")]
+    NegativeAmount { client_id: u16, tx_id: u32 },
+    #[error("adjustment tx {tx_id} of client {client_id} has no reason code")]
+    MissingReason { client_id: u16, tx_id: u32 },
+    #[error("tx {tx_id} is not a known transaction of client {client_id}")]
+    UnknownTx { client_id: u16, tx_id: u32 },
+    #[error("tx {tx_id} of client {client_id} cannot be disputed")]
+    NotDisputable { client_id: u16, tx_id: u32 },
+    #[error("tx {tx_id} of client {client_id} is not under dispute")]
+    NotDisputed { client_id: u16, tx_id: u32 },
+    #[error(
+        "tx {tx_id} of client {client_id} moves more than the {limit} left of the disputed tx"
+    )]
+    ExcessAmount {
+        client_id: u16,
+        tx_id: u32,
+        limit: Amount,
+    },
+    #[error("tx {tx_id} of client {client_id} reuses a tx id that was already seen")]
+    DuplicateTx { client_id: u16, tx_id: u32 },
+    #[error("tx {tx_id} referenced by client {client_id} belongs to client {owner_id}")]
+    ForeignTx {
+        client_id: u16,
+        tx_id: u32,
+        owner_id: u16,
+    },
+    #[error("tx store failed on tx {tx_id} of client {client_id}: {reason}")]
+    Storage {
+        client_id: u16,
+        tx_id: u32,
+        reason: String,
+    },
+}
+
+/// A failure after a transaction was applied or rejected as usual: its outcome stands,
+/// but processing must stop. Kept until `Accounts::take_fatal_error`.
+#[derive(Error, Debug, Clone, Eq, PartialEq)]
+pub enum FatalError {
+    /// the events of the transaction could not be journaled
+    #[error("journal failed on tx {tx_id} of client {client_id}: {reason}")]
+    Journal {
+        client_id: u16,
+        tx_id: u32,
+        reason: String,
+    },
+    /// the transaction left its account breaking an invariant;
+    /// only checked with `Accounts::with_verify_each`
+    #[error("tx {tx_id} of client {client_id} broke invariants: {reason}")]
+    Invariant {
+        client_id: u16,
+        tx_id: u32,
+        reason: String,
+    },
+}
+
+impl TxRejection {
+    /// whether processing should stop, rather than ignore the transaction.
+    /// Only failures of the tx store are fatal; the remainder are per spec.
+    pub fn is_fatal(&self) -> bool {
+        matches!(self, TxRejection::Storage { .. })
+    }
+
+    /// a short name of the variant, for counting rejections by reason
+    pub fn kind(&self) -> &'static str {
+        match self {
+            TxRejection::AccountLocked { .. } => "account_locked",
+            TxRejection::AccountClosed { .. } => "account_closed",
+            TxRejection::UnknownClient { .. } => "unknown_client",
+            TxRejection::NotLocked { .. } => "not_locked",
+            TxRejection::NotEmpty { .. } => "not_empty",
+            TxRejection::AdminOnly { .. } => "admin_only",
+            TxRejection::InsufficientFunds { .. } => "insufficient_funds",
+            TxRejection::Overflow { .. } => "overflow",
+            TxRejection::MissingAmount { .. } => "missing_amount",
+            TxRejection::NegativeAmount { .. } => "negative_amount",
+            TxRejection::MissingReason { .. } => "missing_reason",
+            TxRejection::UnknownTx { .. } => "unknown_tx",
+            TxRejection::NotDisputable { .. } => "not_disputable",
+            TxRejection::NotDisputed { .. } => "not_disputed",
+            TxRejection::ExcessAmount { .. } => "excess_amount",
+            TxRejection::DuplicateTx { .. } => "duplicate_tx",
+            TxRejection::ForeignTx { .. } => "foreign_tx",
+            TxRejection::Storage { .. } => "storage",
+        }
+    }
+}
+
+/// Order in which accounts are written.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Default)]
+pub enum SortKey {
+    /// ascending client id
+    #[default]
+    ClientId,
+    /// ascending total balance
+    Total,
+    /// ascending available balance
+    Available,
+    /// locked accounts first, each group by ascending client id
+    LockedFirst,
+}
+
+impl FromStr for SortKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "client" => Ok(SortKey::ClientId),
+            "total" => Ok(SortKey::Total),
+            "available" => Ok(SortKey::Available),
+            "locked-first" => Ok(SortKey::LockedFirst),
+            _ => bail!(
+                "unknown sort key {:?}, expected one of: client, total, available, locked-first",
+                s
+            ),
+        }
+    }
+}
+
+/// Formats accounts can be written in.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Default)]
+pub enum OutputFormat {
+    /// csv with a header row
+    #[default]
+    Csv,
+    /// one JSON object per account per line. Amounts are strings with exactly 4 decimal places.
+    JsonLines,
+}
+
+impl OutputFormat {
+    /// the format implied by the extension of `path`: `.jsonl` or `.ndjson` for JSON Lines,
+    /// `.csv` for csv. A compression extension after it, like `.gz`, is skipped
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let extension = Compression::strip_extension(path)
+            .extension()?
+            .to_str()?
+            .to_ascii_lowercase();
+        match extension.as_str() {
+            "csv" => Some(OutputFormat::Csv),
+            "jsonl" | "ndjson" => Some(OutputFormat::JsonLines),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "csv" => Ok(OutputFormat::Csv),
+            "jsonl" | "ndjson" => Ok(OutputFormat::JsonLines),
+            _ => bail!("unknown output format {:?}, expected csv or jsonl", s),
+        }
+    }
+}
+
+/// Decides which transactions may be disputed. Chosen when `Accounts` is constructed.
+#[derive(Clone, Default)]
+pub enum DisputePolicy {
+    /// only deposits may be disputed
+    DepositsOnly,
+    /// deposits and withdrawals may be disputed.
+    /// A disputed withdrawal is held as a negative amount.
+    #[default]
+    DepositsAndWithdrawals,
+    /// a transaction may be disputed if the callback returns true.
+    /// Only deposits and withdrawals are ever offered to it.
+    Custom(Arc<dyn Fn(&Transaction) -> bool + Send + Sync>),
+}
+
+impl DisputePolicy {
+    /// whether `tx` may be disputed under this policy
+    pub fn allows(&self, tx: &Transaction) -> bool {
+        match tx.transaction_type {
+            TransactionType::Deposit => match self {
+                DisputePolicy::Custom(allows) => allows(tx),
+                _ => true,
+            },
+            TransactionType::Withdrawal => match self {
+                DisputePolicy::DepositsOnly => false,
+                DisputePolicy::DepositsAndWithdrawals => true,
+                DisputePolicy::Custom(allows) => allows(tx),
+            },
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Debug for DisputePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DisputePolicy::DepositsOnly => f.write_str("DepositsOnly"),
+            DisputePolicy::DepositsAndWithdrawals => f.write_str("DepositsAndWithdrawals"),
+            DisputePolicy::Custom(_) => f.write_str("Custom(..)"),
+        }
+    }
+}
+
+impl PartialEq for DisputePolicy {
+    /// custom policies are equal only if they share the same callback
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (DisputePolicy::DepositsOnly, DisputePolicy::DepositsOnly) => true,
+            (DisputePolicy::DepositsAndWithdrawals, DisputePolicy::DepositsAndWithdrawals) => true,
+            (DisputePolicy::Custom(a), DisputePolicy::Custom(b)) => Arc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for DisputePolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "deposits-only" => Ok(DisputePolicy::DepositsOnly),
+            "deposits-and-withdrawals" => Ok(DisputePolicy::DepositsAndWithdrawals),
+            _ => bail!(
+                "unknown dispute policy {:?}, expected deposits-only or deposits-and-withdrawals",
+                s
+            ),
+        }
+    }
+}
+
+/// Whether tx ids must be unique across the whole ledger or only within each client.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Default)]
+pub enum TxIdScope {
+    /// a tx id may be used by only one deposit or withdrawal of any client (per spec)
+    #[default]
+    Global,
+    /// each client has its own tx id space
+    PerClient,
+}
+
+impl FromStr for TxIdScope {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "global" => Ok(TxIdScope::Global),
+            "per-client" => Ok(TxIdScope::PerClient),
+            _ => bail!("unknown tx id scope {:?}, expected global or per-client", s),
+        }
+    }
+}
+
+/// Which inputs admin operations (adjust, lock, unlock and close) are accepted from.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Default)]
+pub enum AdminPolicy {
+    /// any input, like client transactions
+    #[default]
+    Anywhere,
+    /// only the admin input, see `Accounts::process_admin_transaction`;
+    /// elsewhere they are rejected
+    AdminInputOnly,
+}
+
+impl FromStr for AdminPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "anywhere" => Ok(AdminPolicy::Anywhere),
+            "admin-input-only" => Ok(AdminPolicy::AdminInputOnly),
+            _ => bail!(
+                "unknown admin policy {:?}, expected anywhere or admin-input-only",
+                s
+            ),
+        }
+    }
+}
+
+/// tx ids of every deposit, withdrawal and admin operation seen so far, applied or not, used to reject duplicates.
+/// Kept in memory regardless of the tx store; a few bytes per tx id.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SeenTxIds {
+    /// tx id -> client that used it
+    Global(HashMap<u32, u16>),
+    /// (client, tx id)
+    PerClient(HashSet<(u16, u32)>),
+}
+
+impl Default for SeenTxIds {
+    fn default() -> Self {
+        SeenTxIds::new(TxIdScope::default())
+    }
+}
+
+impl SeenTxIds {
+    pub fn new(scope: TxIdScope) -> Self {
+        match scope {
+            TxIdScope::Global => SeenTxIds::Global(HashMap::new()),
+            TxIdScope::PerClient => SeenTxIds::PerClient(HashSet::new()),
+        }
+    }
+
+    pub fn scope(&self) -> TxIdScope {
+        match self {
+            SeenTxIds::Global(_) => TxIdScope::Global,
+            SeenTxIds::PerClient(_) => TxIdScope::PerClient,
+        }
+    }
+
+    /// records that `client_id` used `tx_id`. Returns false if it was already used.
+    pub fn insert(&mut self, client_id: u16, tx_id: u32) -> bool {
+        match self {
+            SeenTxIds::Global(owners) => match owners.entry(tx_id) {
+                Entry::Occupied(_) => false,
+                Entry::Vacant(entry) => {
+                    entry.insert(client_id);
+                    true
+                }
+            },
+            SeenTxIds::PerClient(seen) => seen.insert((client_id, tx_id)),
+        }
+    }
+
+    /// the client that used `tx_id`, if that is a client other than `client_id`.
+    /// Always `None` when tx ids are per client.
+    pub fn foreign_owner(&self, client_id: u16, tx_id: u32) -> Option<u16> {
+        match self {
+            SeenTxIds::Global(owners) => owners
+                .get(&tx_id)
+                .copied()
+                .filter(|owner| *owner != client_id),
+            SeenTxIds::PerClient(_) => None,
+        }
+    }
+
+    /// every (client, tx id) seen, ordered by client id then tx id
+    pub fn sorted(&self) -> Vec<(u16, u32)> {
+        let mut seen: Vec<(u16, u32)> = match self {
+            SeenTxIds::Global(owners) => owners
+                .iter()
+                .map(|(tx_id, client_id)| (*client_id, *tx_id))
+                .collect(),
+            SeenTxIds::PerClient(seen) => seen.iter().copied().collect(),
+        };
+        seen.sort_unstable();
+        seen
+    }
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
 pub struct Account {
     #[serde(rename = "client")]
     pub client_id: u16,
-    #[serde(
-        rename = "available",
-        serialize_with = "serialize_floating_point",
-        deserialize_with = "deserialize_floating_point"
-    )]
-    pub avail_bal: FloatingPoint,
-    #[serde(
-        rename = "held",
-        serialize_with = "serialize_floating_point",
-        deserialize_with = "deserialize_floating_point"
-    )]
-    pub held_bal: FloatingPoint,
-    #[serde(
-        rename = "total",
-        serialize_with = "serialize_floating_point",
-        deserialize_with = "deserialize_floating_point"
-    )]
-    pub total_bal: FloatingPoint,
+    #[serde(rename = "available")]
+    pub avail_bal: Amount,
+    #[serde(rename = "held")]
+    pub held_bal: Amount,
+    #[serde(rename = "total")]
+    pub total_bal: Amount,
     pub locked: bool,
-    // list of transactions. id -> Transaction
-    // Needed in the case of a dispute
-    #[serde(skip_serializing)]
-    pub transactions: HashMap<u32, Transaction>,
-    // set of unresolved disputes
+    // unresolved disputes: the (unsigned) part of each disputed tx that is held
     #[serde(skip_serializing)]
-    pub disputes: HashSet<u32>,
+    pub disputes: HashMap<u32, Amount>,
+    // the (unsigned) part of each tx that was charged back
+    #[serde(skip)]
+    pub charged_back_txs: HashMap<u32, Amount>,
+    // net amount chargebacks have removed from the total balance
+    #[serde(skip)]
+    pub charged_back: Amount,
+    // closed for good by an admin; also locked
+    #[serde(skip)]
+    pub closed: bool,
 }
 
 impl Account {
     fn new(client_id: u16) -> Self {
         Account {
             client_id,
-            avail_bal: FloatingPoint::from_num(0),
-            held_bal: FloatingPoint::from_num(0),
-            total_bal: FloatingPoint::from_num(0),
+            avail_bal: Amount::ZERO,
+            held_bal: Amount::ZERO,
+            total_bal: Amount::ZERO,
             locked: false,
-            transactions: HashMap::new(),
-            disputes: HashSet::new(),
+            disputes: HashMap::new(),
+            charged_back_txs: HashMap::new(),
+            charged_back: Amount::ZERO,
+            closed: false,
         }
     }
 }
 
-/// Represents a set of accounts. Internal rep is a map from account id to account metadata
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
-pub struct Accounts {
+/// Represents a set of accounts. Internal rep is a map from account id to account metadata.
+/// Deposits and withdrawals are kept in `store` in case they are disputed.
+#[derive(Debug, Clone, Default)]
+pub struct Accounts<S = InMemoryTxStore> {
     pub state: HashMap<u16, Account>,
+    pub store: S,
+    pub dispute_policy: DisputePolicy,
+    pub admin_policy: AdminPolicy,
+    pub seen_tx_ids: SeenTxIds,
+    /// counts of what happened since these accounts were created or loaded
+    pub stats: Stats,
+    /// check the invariants of the account after every transaction, see `Accounts::verify_client`
+    pub verify_each: bool,
+    /// where every change to an account is recorded, if anywhere
+    pub journal: Option<Journal>,
+    /// the first failure of the journal or the invariant check, see `Accounts::take_fatal_error`
+    pub fatal_error: Option<FatalError>,
+}
+
+// stats, the debug check, the journal and its failure describe a run rather than the state,
+// so they are left out
+impl<S: PartialEq> PartialEq for Accounts<S> {
+    fn eq(&self, other: &Self) -> bool {
+        self.state == other.state
+            && self.store == other.store
+            && self.dispute_policy == other.dispute_policy
+            && self.admin_policy == other.admin_policy
+            && self.seen_tx_ids == other.seen_tx_ids
+    }
 }
 
 impl Accounts {
-    /// creates new set of Accounts
+    /// creates new set of Accounts that keeps transaction history in memory
     pub fn new() -> Self {
+        Accounts::with_store(InMemoryTxStore::new())
+    }
+}
+
+impl<S: TxStore> Accounts<S> {
+    /// creates new set of Accounts that keeps transaction history in `store`
+    pub fn with_store(store: S) -> Self {
         Accounts {
             state: HashMap::new(),
+            store,
+            dispute_policy: DisputePolicy::default(),
+            admin_policy: AdminPolicy::default(),
+            seen_tx_ids: SeenTxIds::default(),
+            stats: Stats::default(),
+            verify_each: false,
+            journal: None,
+            fatal_error: None,
         }
     }
 
-    /// serialize state and prints to stdout
+    /// sets which transactions may be disputed
+    pub fn with_dispute_policy(mut self, dispute_policy: DisputePolicy) -> Self {
+        self.dispute_policy = dispute_policy;
+        self
+    }
+
+    /// sets which inputs admin operations are accepted from
+    pub fn with_admin_policy(mut self, admin_policy: AdminPolicy) -> Self {
+        self.admin_policy = admin_policy;
+        self
+    }
+
+    /// checks the invariants of the account after every transaction; the first violation
+    /// is kept as a `FatalError::Invariant`. Slow; meant for debugging
+    pub fn with_verify_each(mut self, verify_each: bool) -> Self {
+        self.verify_each = verify_each;
+        self
+    }
+
+    /// records every change made by `process_transaction` to `journal`
+    pub fn with_journal(mut self, journal: Journal) -> Self {
+        self.journal = Some(journal);
+        self
+    }
+
+    /// sets whether tx ids are unique across the ledger or per client.
+    /// Forgets any tx ids seen so far, so set it before processing.
+    pub fn with_tx_id_scope(mut self, scope: TxIdScope) -> Self {
+        self.seen_tx_ids = SeenTxIds::new(scope);
+        self
+    }
+
+    /// serialize state ordered by client id and prints to stdout
     /// overwrites any existing data
     /// throws error if unable to serialize
     pub fn serialize_to_writer(&self, w: impl Write) -> Result<()> {
+        self.serialize_to_writer_sorted(w, SortKey::ClientId)
+    }
+
+    /// serialize state ordered by `key`, see `serialize_to_writer`
+    pub fn serialize_to_writer_sorted(&self, w: impl Write, key: SortKey) -> Result<()> {
         // better to be explicit in case library defaults change
         let mut wtr = WriterBuilder::new()
             .delimiter(b',')
             .has_headers(false)
             .from_writer(w);
         wtr.write_record(vec!["client", "available", "held", "total", "locked"])?;
-        for (_, account) in self.state.iter() {
+        for account in self.sorted(key) {
             wtr.serialize(account)?;
         }
         wtr.flush()?;
         Ok(())
     }
 
+    /// writes one JSON object per account per line, ordered by `key`
+    pub fn serialize_json_lines_sorted(&self, mut w: impl Write, key: SortKey) -> Result<()> {
+        for account in self.sorted(key) {
+            serde_json::to_writer(&mut w, account)?;
+            w.write_all(b"\n")?;
+        }
+        w.flush()?;
+        Ok(())
+    }
+
+    /// every account ordered by `key`. Ties are broken by client id so the order is total.
+    pub fn sorted(&self, key: SortKey) -> Vec<&Account> {
+        let mut accounts: Vec<&Account> = self.state.values().collect();
+        match key {
+            SortKey::ClientId => accounts.sort_by_key(|a| a.client_id),
+            SortKey::Total => accounts.sort_by_key(|a| (a.total_bal, a.client_id)),
+            SortKey::Available => accounts.sort_by_key(|a| (a.avail_bal, a.client_id)),
+            SortKey::LockedFirst => accounts.sort_by_key(|a| (!a.locked, a.client_id)),
+        }
+        accounts
+    }
+
+    /// the account of `client_id`, created empty if it has never transacted
+    pub fn touch(&mut self, client_id: u16) -> &mut Account {
+        self.state
+            .entry(client_id)
+            .or_insert_with(|| Account::new(client_id))
+    }
+
+    /// checks the tx id of `t` against every tx id seen so far.
+    /// A deposit, withdrawal or admin operation uses up its tx id; a reused one is rejected
+    /// as a duplicate.
+    /// Other types may not reference another client's tx when tx ids are global.
+    /// Duplicates are rejected whatever the state of the account, so replayed rows never apply.
+    pub fn claim_tx_id(&mut self, t: &Transaction) -> Result<(), TxRejection> {
+        let (client_id, tx_id) = (t.client_id, t.tx_id);
+        if t.transaction_type.claims_tx_id() {
+            if !self.seen_tx_ids.insert(client_id, tx_id) {
+                return Err(TxRejection::DuplicateTx { client_id, tx_id });
+            }
+        } else if let Some(owner_id) = self.seen_tx_ids.foreign_owner(client_id, tx_id) {
+            return Err(TxRejection::ForeignTx {
+                client_id,
+                tx_id,
+                owner_id,
+            });
+        }
+        Ok(())
+    }
+
+    /// moves the accounts with `client_id % shards == shard` into new Accounts backed by `store`,
+    /// copying their transaction history and seen tx ids. The new Accounts track tx ids per client;
+    /// with global tx ids, the caller must check them with `claim_tx_id` before handing rows over.
+    /// If the store fails, `self` is left as it was.
+    pub fn split_shard<T: TxStore>(
+        &mut self,
+        shard: usize,
+        shards: usize,
+        store: T,
+    ) -> Result<Accounts<T>> {
+        let in_shard = |client_id: u16| usize::from(client_id) % shards == shard;
+        let mut split = Accounts::with_store(store)
+            .with_dispute_policy(self.dispute_policy.clone())
+            .with_admin_policy(self.admin_policy)
+            .with_tx_id_scope(TxIdScope::PerClient)
+            .with_verify_each(self.verify_each);
+        split.journal = self.journal.clone();
+        self.store.for_each(&mut |tx| {
+            if in_shard(tx.client_id) {
+                split.store.insert(tx)?;
+            }
+            Ok(())
+        })?;
+        for (client_id, tx_id) in self.seen_tx_ids.sorted() {
+            if in_shard(client_id) {
+                split.seen_tx_ids.insert(client_id, tx_id);
+            }
+        }
+        // moved last, so a failing store leaves `self` as it was
+        let client_ids: Vec<u16> = self
+            .state
+            .keys()
+            .copied()
+            .filter(|client_id| in_shard(*client_id))
+            .collect();
+        for client_id in client_ids {
+            if let Some(account) = self.state.remove(&client_id) {
+                split.state.insert(client_id, account);
+            }
+        }
+        Ok(split)
+    }
+
+    /// moves every account of `shard` (from `split_shard`) back into `self`
+    pub fn absorb<T: TxStore>(&mut self, shard: Accounts<T>) -> Result<()> {
+        self.state.extend(shard.state);
+        self.stats.merge(&shard.stats);
+        if self.fatal_error.is_none() {
+            self.fatal_error = shard.fatal_error;
+        }
+        shard.store.for_each(&mut |tx| self.store.insert(tx))?;
+        // global tx ids were already claimed in `self` before the rows were handed over
+        if self.seen_tx_ids.scope() == TxIdScope::PerClient {
+            for (client_id, tx_id) in shard.seen_tx_ids.sorted() {
+                self.seen_tx_ids.insert(client_id, tx_id);
+            }
+        }
+        Ok(())
+    }
+
     /// mutates `self` to reflect transaction `t`
     /// specification:
-    /// - if an account is frozen, this function is a noop.
+    /// - a deposit or withdrawal reusing a tx id that was already seen is rejected.
+    /// - with globally unique tx ids, referencing another client's tx is rejected.
+    /// - if an account is frozen, the transaction is rejected, unless it resolves or charges
+    ///   back a dispute that is still open, e.g. the rest of a partial chargeback.
     /// - Deposit: adds amount to the account's total balance and available balance
     /// - Withdrawal: subtracts amount from the account's total balance and available balance
-    /// - Dispute: if the disputed tx exists and is a deposit, move the amount
+    /// - Dispute: if the disputed tx exists and the dispute policy allows it, move the amount
     ///   from the available balance to the held balance.
     /// - Resolve: money is returned from the held balance to the avail balance
     /// - Chargeback: money is removed from the held balance and total balance.
-    pub fn process_transaction(&mut self, t: &Transaction) {
+    ///
+    /// a dispute, resolve or chargeback with an amount moves only that part of the disputed tx:
+    /// a dispute at most what is neither held nor charged back yet, a resolve or chargeback
+    /// at most what is held. Without an amount, a dispute holds all that is left of the tx
+    /// and a resolve or chargeback moves all that is held.
+    ///
+    /// admin operations are rejected on a client without an account, which they do not
+    /// create, and if the admin policy keeps them to the admin input,
+    /// see `Accounts::process_admin_transaction`; otherwise:
+    /// - Adjust: adds the signed amount to the available and total balances,
+    ///   even if the account is frozen or the available balance goes negative.
+    /// - Lock: freezes the account.
+    /// - Unlock: unfreezes a frozen account.
+    /// - Close: freezes an account without funds or open disputes for good.
+    ///   A closed account rejects every transaction, admin operations included.
+    ///
+    /// returns what was applied, or the reason the transaction was ignored.
+    /// A rejected transaction leaves the account untouched. Either way it is counted in `stats`
+    /// and its events are recorded in the `journal`. If that fails, or the account breaks an
+    /// invariant with `verify_each`, the outcome still stands and the failure is kept for
+    /// `take_fatal_error`, which callers check to stop processing.
+    pub fn process_transaction(&mut self, t: &Transaction) -> Result<Applied, TxRejection> {
+        self.process(t, false)
+    }
+
+    /// mutates `self` to reflect `t`, read from the admin input. Like `process_transaction`,
+    /// but admin operations are accepted whatever the admin policy
+    pub fn process_admin_transaction(&mut self, t: &Transaction) -> Result<Applied, TxRejection> {
+        self.process(t, true)
+    }
+
+    fn process(&mut self, t: &Transaction, admin_input: bool) -> Result<Applied, TxRejection> {
+        let before = self.state.get(&t.client_id).map(Balances::of);
+        let result = self.apply(t, admin_input);
+        let created = before.is_none() && self.state.contains_key(&t.client_id);
+        let locked = !before.is_some_and(|before| before.locked)
+            && self
+                .state
+                .get(&t.client_id)
+                .is_some_and(|account| account.locked);
+        let total = |accounts: &Self| {
+            accounts
+                .state
+                .get(&t.client_id)
+                .map_or(Amount::ZERO, |account| account.total_bal)
+        };
+        // both totals are valid balances an applied amount apart, so this cannot overflow
+        let moved = total(self)
+            .checked_sub(before.map_or(Amount::ZERO, |before| before.total))
+            .unwrap_or(Amount::ZERO);
+        self.stats.record(&result, created, locked, moved);
+        self.record_events(t, before, &result);
+        if self.verify_each {
+            self.check_verified(t);
+        }
+        result
+    }
+
+    /// the first journal failure or broken invariant since the last call, if any.
+    /// Processing should stop at it: the journal or the account can no longer be trusted
+    pub fn take_fatal_error(&mut self) -> Option<FatalError> {
+        self.fatal_error.take()
+    }
+
+    /// keeps `fatal_error` unless an earlier one is still there
+    pub(crate) fn fail(&mut self, fatal_error: FatalError) {
+        if self.fatal_error.is_none() {
+            self.fatal_error = Some(fatal_error);
+        }
+    }
+
+    /// counts `t` as rejected for `rejection` without applying it,
+    /// as `process_transaction` would; the account is still created,
+    /// unless `t` is an admin operation
+    pub fn reject(&mut self, t: &Transaction, rejection: TxRejection) -> TxRejection {
+        let before = self.state.get(&t.client_id).map(Balances::of);
+        let admin = t.transaction_type.is_admin();
+        if before.is_some() || !admin {
+            self.touch(t.client_id);
+        }
+        let result = Err(rejection.clone());
+        let created = before.is_none() && !admin;
+        self.stats.record(&result, created, false, Amount::ZERO);
+        self.record_events(t, before, &result);
+        rejection
+    }
+
+    /// writes the events of `t` to the journal, if any, keeping a failure as a `FatalError`
+    fn record_events(
+        &mut self,
+        t: &Transaction,
+        before: Option<Balances>,
+        result: &Result<Applied, TxRejection>,
+    ) {
+        let journal = match &self.journal {
+            Some(journal) => journal,
+            None => return,
+        };
+        // an admin operation on a client without an account changes nothing
+        let Some(account) = self.state.get(&t.client_id) else {
+            return;
+        };
+        let events = journal::events(t, before, Balances::of(account), result);
+        if events.is_empty() {
+            return;
+        }
+        if let Err(err) = journal.record(&events) {
+            self.fail(FatalError::Journal {
+                client_id: t.client_id,
+                tx_id: t.tx_id,
+                reason: err.to_string(),
+            });
+        }
+    }
+
+    /// the stats of the run with the money that moved, see `Summary`
+    pub fn summary(&self) -> Summary {
+        let money_held = self.state.values().fold(Amount::ZERO, |held, account| {
+            held.saturating_add(account.held_bal)
+        });
+        Summary {
+            rows_read: self.stats.rows_read(),
+            stats: self.stats.clone(),
+            money_held,
+        }
+    }
+
+    fn apply(&mut self, t: &Transaction, admin_input: bool) -> Result<Applied, TxRejection> {
+        let (client_id, tx_id) = (t.client_id, t.tx_id);
+        let admin = t.transaction_type.is_admin();
+        // an admin operation neither opens an account nor uses up a tx id without one
+        if admin && !self.state.contains_key(&client_id) {
+            return Err(TxRejection::UnknownClient { client_id, tx_id });
+        }
+        let claimed = self.claim_tx_id(t);
         let account = self
             .state
-            .entry(t.client_id)
-            .or_insert_with(|| Account::new(t.client_id));
-        // "frozen" means transactions are no longer processed
-        if account.locked {
-            return;
+            .entry(client_id)
+            .or_insert_with(|| Account::new(client_id));
+        claimed?;
+        if admin && !admin_input && self.admin_policy == AdminPolicy::AdminInputOnly {
+            return Err(TxRejection::AdminOnly { client_id, tx_id });
+        }
+        if account.closed {
+            return Err(TxRejection::AccountClosed { client_id, tx_id });
+        }
+        // "frozen" means client transactions are no longer processed,
+        // but disputes that are still open can be settled
+        let settles = matches!(
+            t.transaction_type,
+            TransactionType::Resolve | TransactionType::Chargeback
+        ) && account.disputes.contains_key(&tx_id);
+        if account.locked && !admin && !settles {
+            return Err(TxRejection::AccountLocked { client_id, tx_id });
         }
         match t.transaction_type {
             TransactionType::Deposit | TransactionType::Withdrawal => {
-                // we check the state during parsing.
-                // This can never be empty
-                let amount = t.amount.unwrap();
+                // we check the state during parsing, but don't trust the caller
+                let amount = t
+                    .amount
+                    .ok_or(TxRejection::MissingAmount { client_id, tx_id })?;
+                // the sign comes from the type; a negative withdrawal would be a deposit
+                if amount.is_negative() {
+                    return Err(TxRejection::NegativeAmount { client_id, tx_id });
+                }
                 let sign = t.transaction_type.get_sign();
-                // balance doesn't go negative or become infinite; otherwise noop.
-                let new_total_bal = amount
-                    .0
-                    .checked_mul(sign)
-                    .and_then(|x| x.checked_add(account.total_bal));
-                let new_avail_bal = amount
-                    .0
+                // balance doesn't go negative or become infinite; otherwise rejected.
+                let signed_amnt = amount
                     .checked_mul(sign)
-                    .and_then(|x| x.checked_add(account.avail_bal));
-                if new_total_bal.is_some()
-                    && new_avail_bal.is_some()
-                    // may deposit to negative available balance
-                    // may not withdraw from negative available balance
-                    && (t.transaction_type == TransactionType::Deposit
-                        || new_avail_bal.unwrap() >= FloatingPoint::from_num(0))
+                    .ok_or(TxRejection::Overflow { client_id, tx_id })?;
+                let new_total_bal = signed_amnt
+                    .checked_add(account.total_bal)
+                    .ok_or(TxRejection::Overflow { client_id, tx_id })?;
+                let new_avail_bal = signed_amnt
+                    .checked_add(account.avail_bal)
+                    .ok_or(TxRejection::Overflow { client_id, tx_id })?;
+                // may deposit to negative available balance
+                // may not withdraw from negative available balance
+                if t.transaction_type == TransactionType::Withdrawal && new_avail_bal.is_negative()
                 {
-                    account.total_bal = new_total_bal.unwrap();
-                    account.avail_bal = new_avail_bal.unwrap();
-                    // will not be overwriting because tx ids are assumed to be unique per spec
-                    account.transactions.insert(t.tx_id, *t);
+                    return Err(TxRejection::InsufficientFunds { client_id, tx_id });
                 }
+                // will not be overwriting because duplicate tx ids are rejected above
+                self.store.insert(*t).map_err(|err| TxRejection::Storage {
+                    client_id,
+                    tx_id,
+                    reason: err.to_string(),
+                })?;
+                account.total_bal = new_total_bal;
+                account.avail_bal = new_avail_bal;
+                Ok(match t.transaction_type {
+                    TransactionType::Deposit => Applied::Deposit(amount),
+                    _ => Applied::Withdrawal(amount),
+                })
             }
             TransactionType::Dispute => {
-                if let Some(disputed_tx) = account.transactions.get(&t.tx_id) {
-                    // Only deposits and withdrawals can be disputed
-                    if disputed_tx.transaction_type == TransactionType::Deposit
-                        || disputed_tx.transaction_type == TransactionType::Withdrawal
-                    {
-                        if let Some(disputed_amount) = disputed_tx.amount {
-                            let sign = disputed_tx.transaction_type.get_sign();
-                            let signed_amnt_wr = disputed_amount.0.checked_mul(sign);
-                            let new_avail_bal = signed_amnt_wr
-                                .and_then(|signed_amnt| account.avail_bal.checked_sub(signed_amnt));
-                            let new_held_bal = signed_amnt_wr
-                                .and_then(|signed_amnt| account.held_bal.checked_add(signed_amnt));
-                            if new_held_bal.is_some() && new_avail_bal.is_some() {
-                                account.avail_bal = new_avail_bal.unwrap();
-                                account.held_bal = new_held_bal.unwrap();
-                                account.disputes.insert(disputed_tx.tx_id);
-                            }
-                        }
-                    }
+                let disputed_tx = self
+                    .store
+                    .get(client_id, tx_id)
+                    .map_err(|err| TxRejection::Storage {
+                        client_id,
+                        tx_id,
+                        reason: err.to_string(),
+                    })?
+                    .ok_or(TxRejection::UnknownTx { client_id, tx_id })?;
+                if !self.dispute_policy.allows(&disputed_tx) {
+                    return Err(TxRejection::NotDisputable { client_id, tx_id });
+                }
+                let held = account
+                    .disputes
+                    .get(&tx_id)
+                    .copied()
+                    .unwrap_or(Amount::ZERO);
+                let charged_back = account
+                    .charged_back_txs
+                    .get(&tx_id)
+                    .copied()
+                    .unwrap_or(Amount::ZERO);
+                let full_amount = disputed_tx
+                    .amount
+                    .ok_or(TxRejection::MissingAmount { client_id, tx_id })?;
+                let disputable = full_amount
+                    .checked_sub(held)
+                    .and_then(|left| left.checked_sub(charged_back))
+                    .ok_or(TxRejection::Overflow { client_id, tx_id })?;
+                // nothing left to dispute, e.g. already disputed or charged back in full,
+                // even once the account is unlocked
+                if disputable == Amount::ZERO {
+                    return Err(TxRejection::NotDisputable { client_id, tx_id });
+                }
+                let (disputed_amount, signed_amnt) = moved_amount(t, &disputed_tx, disputable)?;
+                let new_avail_bal = account
+                    .avail_bal
+                    .checked_sub(signed_amnt)
+                    .ok_or(TxRejection::Overflow { client_id, tx_id })?;
+                let new_held_bal = account
+                    .held_bal
+                    .checked_add(signed_amnt)
+                    .ok_or(TxRejection::Overflow { client_id, tx_id })?;
+                account.avail_bal = new_avail_bal;
+                account.held_bal = new_held_bal;
+                // cannot overflow, it stays within the amount of the tx
+                account
+                    .disputes
+                    .insert(tx_id, held.saturating_add(disputed_amount));
+                Ok(Applied::Dispute(disputed_amount))
+            }
+            TransactionType::Resolve | TransactionType::Chargeback => {
+                let disputed_tx = self
+                    .store
+                    .get(client_id, tx_id)
+                    .map_err(|err| TxRejection::Storage {
+                        client_id,
+                        tx_id,
+                        reason: err.to_string(),
+                    })?
+                    .ok_or(TxRejection::UnknownTx { client_id, tx_id })?;
+                // should always be true in order for it to be marked as disputed
+                let held = *account
+                    .disputes
+                    .get(&tx_id)
+                    .ok_or(TxRejection::NotDisputed { client_id, tx_id })?;
+                let (disputed_amount, signed_amnt) = moved_amount(t, &disputed_tx, held)?;
+                let new_held_bal = account
+                    .held_bal
+                    .checked_sub(signed_amnt)
+                    .ok_or(TxRejection::Overflow { client_id, tx_id })?;
+                // the dispute stays open while part of the tx is still held
+                let still_held = held
+                    .checked_sub(disputed_amount)
+                    .ok_or(TxRejection::Overflow { client_id, tx_id })?;
+                if t.transaction_type == TransactionType::Resolve {
+                    let new_avail_bal = account
+                        .avail_bal
+                        .checked_add(signed_amnt)
+                        .ok_or(TxRejection::Overflow { client_id, tx_id })?;
+                    account.held_bal = new_held_bal;
+                    account.avail_bal = new_avail_bal;
+                    update_dispute(account, tx_id, still_held);
+                    Ok(Applied::Resolve(disputed_amount))
+                } else {
+                    let new_total_bal = account
+                        .total_bal
+                        .checked_sub(signed_amnt)
+                        .ok_or(TxRejection::Overflow { client_id, tx_id })?;
+                    let charged_back = account
+                        .charged_back
+                        .checked_add(signed_amnt)
+                        .ok_or(TxRejection::Overflow { client_id, tx_id })?;
+                    account.held_bal = new_held_bal;
+                    account.total_bal = new_total_bal;
+                    account.charged_back = charged_back;
+                    // cannot overflow, it stays within the amount of the tx
+                    let tx_charged_back = account.charged_back_txs.entry(tx_id).or_default();
+                    *tx_charged_back = tx_charged_back.saturating_add(disputed_amount);
+                    update_dispute(account, tx_id, still_held);
+                    account.locked = true;
+                    Ok(Applied::Chargeback(disputed_amount))
+                }
+            }
+            TransactionType::Adjust => {
+                let amount = t
+                    .amount
+                    .ok_or(TxRejection::MissingAmount { client_id, tx_id })?;
+                if t.reason.is_none() {
+                    return Err(TxRejection::MissingReason { client_id, tx_id });
                 }
+                let new_total_bal = account
+                    .total_bal
+                    .checked_add(amount)
+                    .ok_or(TxRejection::Overflow { client_id, tx_id })?;
+                let new_avail_bal = account
+                    .avail_bal
+                    .checked_add(amount)
+                    .ok_or(TxRejection::Overflow { client_id, tx_id })?;
+                // kept with the deposits and withdrawals, so the history accounts for the total
+                self.store.insert(*t).map_err(|err| TxRejection::Storage {
+                    client_id,
+                    tx_id,
+                    reason: err.to_string(),
+                })?;
+                account.total_bal = new_total_bal;
+                account.avail_bal = new_avail_bal;
+                Ok(Applied::Adjustment(amount))
             }
-            TransactionType::Resolve => {
-                if let Some(disputed_tx) = account.transactions.get(&t.tx_id) {
-                    // should always be true in order for it to be marked as disputed
-                    if account.disputes.contains(&disputed_tx.tx_id) {
-                        if let Some(disputed_amount) = disputed_tx.amount {
-                            let sign = disputed_tx.transaction_type.get_sign();
-                            let signed_amnt_wr = disputed_amount.0.checked_mul(sign);
-                            let new_held_bal = signed_amnt_wr
-                                .and_then(|signed_amnt| account.held_bal.checked_sub(signed_amnt));
-                            let new_avail_bal = signed_amnt_wr
-                                .and_then(|signed_amnt| account.avail_bal.checked_add(signed_amnt));
-                            if new_avail_bal.is_some() && new_held_bal.is_some() {
-                                account.held_bal = new_held_bal.unwrap();
-                                account.avail_bal = new_avail_bal.unwrap();
-                                account.disputes.remove(&disputed_tx.tx_id);
-                            }
-                        }
-                    }
+            TransactionType::Lock => {
+                if account.locked {
+                    return Err(TxRejection::AccountLocked { client_id, tx_id });
                 }
+                account.locked = true;
+                Ok(Applied::Lock)
             }
-            TransactionType::Chargeback => {
-                if let Some(disputed_tx) = account.transactions.get(&t.tx_id) {
-                    // should always be true in order for it to be marked as disputed
-                    if account.disputes.contains(&disputed_tx.tx_id) {
-                        if let Some(disputed_amount) = disputed_tx.amount {
-                            let sign = disputed_tx.transaction_type.get_sign();
-                            let signed_amnt_wr = disputed_amount.0.checked_mul(sign);
-                            let new_held_bal = signed_amnt_wr
-                                .and_then(|signed_amnt| account.held_bal.checked_sub(signed_amnt));
-                            let new_total_bal = signed_amnt_wr
-                                .and_then(|signed_amnt| account.total_bal.checked_sub(signed_amnt));
-                            if new_total_bal.is_some() && new_held_bal.is_some() {
-                                account.held_bal = new_held_bal.unwrap();
-                                account.total_bal = new_total_bal.unwrap();
-                                account.disputes.remove(&disputed_tx.tx_id);
-                                account.locked = true;
-                            }
-                        }
-                    }
+            TransactionType::Unlock => {
+                if !account.locked {
+                    return Err(TxRejection::NotLocked { client_id, tx_id });
                 }
+                account.locked = false;
+                Ok(Applied::Unlock)
+            }
+            TransactionType::Close => {
+                if account.total_bal != Amount::ZERO
+                    || account.held_bal != Amount::ZERO
+                    || !account.disputes.is_empty()
+                {
+                    return Err(TxRejection::NotEmpty { client_id, tx_id });
+                }
+                account.closed = true;
+                account.locked = true;
+                Ok(Applied::Close)
             }
         }
     }
 }
 
-/// Function to serialize floating point to 4 digits of precision.
-///
-fn serialize_floating_point<S>(f: &FloatingPoint, serializer: S) -> Result<S::Ok, S::Error>
-where
-    S: Serializer,
-{
-    serializer.serialize_str(&format!("{:.4}", f))
+/// the part of `disputed_tx` that the dispute, resolve or chargeback `t` moves between
+/// the available and held balances, as (unsigned, signed): its amount, at most `limit`,
+/// or all of `limit` if it has none.
+/// For a deposit the signed amount is positive; for a withdrawal it is negative.
+fn moved_amount(
+    t: &Transaction,
+    disputed_tx: &Transaction,
+    limit: Amount,
+) -> Result<(Amount, Amount), TxRejection> {
+    let (client_id, tx_id) = (t.client_id, t.tx_id);
+    let amount = t.amount.unwrap_or(limit);
+    // we check the state during parsing, but don't trust the caller
+    if amount <= Amount::ZERO {
+        return Err(TxRejection::NotDisputable { client_id, tx_id });
+    }
+    if amount > limit {
+        return Err(TxRejection::ExcessAmount {
+            client_id,
+            tx_id,
+            limit,
+        });
+    }
+    let signed_amnt = amount
+        .checked_mul(disputed_tx.transaction_type.get_sign())
+        .ok_or(TxRejection::Overflow { client_id, tx_id })?;
+    Ok((amount, signed_amnt))
+}
+
+/// the parts of txs held or charged back, by tx id
+pub(crate) fn sorted_by_tx(amounts: &HashMap<u32, Amount>) -> Vec<(u32, Amount)> {
+    let mut sorted: Vec<(u32, Amount)> = amounts.iter().map(|(tx_id, a)| (*tx_id, *a)).collect();
+    sorted.sort_unstable();
+    sorted
+}
+
+/// sets the part of tx `tx_id` still held by its dispute, closing the dispute if nothing is
+fn update_dispute(account: &mut Account, tx_id: u32, still_held: Amount) {
+    if still_held == Amount::ZERO {
+        account.disputes.remove(&tx_id);
+    } else {
+        account.disputes.insert(tx_id, still_held);
+    }
 }
@@ -0,0 +1,189 @@
+use serde::{
+    self,
+    de::{Error as DeError, Visitor},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+use std::fmt;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// Amount of money with exactly 4 decimal places.
+pub type Amount = Decimal<4>;
+
+/// Exact decimal number with `PLACES` digits past the decimal point.
+/// Internal rep is the number of `10^-PLACES` units, so `1.5` with 4 places is stored as `15000`.
+/// Arithmetic is checked, overflow is reported rather than wrapped.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Decimal<const PLACES: u32> {
+    units: i128,
+}
+
+/// Reasons a string is not a valid decimal amount.
+#[derive(Error, Debug, Clone, Eq, PartialEq)]
+pub enum ParseAmountError {
+    #[error("amount is empty")]
+    Empty,
+    #[error("amount contains invalid character {0:?}")]
+    InvalidCharacter(char),
+    #[error("amount must be written without exponent notation")]
+    Exponent,
+    #[error("amount must be a finite number")]
+    NotFinite,
+    #[error("amount has more than {0} decimal places")]
+    TooPrecise(u32),
+    #[error("amount is out of range")]
+    Overflow,
+}
+
+impl<const PLACES: u32> Decimal<PLACES> {
+    /// number of units in `1`
+    pub const SCALE: i128 = 10i128.pow(PLACES);
+    pub const ZERO: Self = Decimal { units: 0 };
+
+    /// creates a decimal from a count of `10^-PLACES` units
+    pub const fn from_units(units: i128) -> Self {
+        Decimal { units }
+    }
+
+    /// the count of `10^-PLACES` units
+    pub const fn units(self) -> i128 {
+        self.units
+    }
+
+    pub fn checked_add(self, rhs: Self) -> Option<Self> {
+        self.units.checked_add(rhs.units).map(Self::from_units)
+    }
+
+    pub fn checked_sub(self, rhs: Self) -> Option<Self> {
+        self.units.checked_sub(rhs.units).map(Self::from_units)
+    }
+
+    /// adds, stopping at the largest or smallest value instead of overflowing
+    pub fn saturating_add(self, rhs: Self) -> Self {
+        Self::from_units(self.units.saturating_add(rhs.units))
+    }
+
+    /// multiplies by an integer (e.g. a sign)
+    pub fn checked_mul(self, rhs: i128) -> Option<Self> {
+        self.units.checked_mul(rhs).map(Self::from_units)
+    }
+
+    pub fn is_negative(self) -> bool {
+        self.units < 0
+    }
+}
+
+impl<const PLACES: u32> FromStr for Decimal<PLACES> {
+    type Err = ParseAmountError;
+
+    /// parses the string digit by digit, so no precision is lost.
+    /// Accepts an optional sign, then digits with an optional `.`.
+    /// Trailing zeros past `PLACES` are allowed since they do not change the value.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (negative, digits) = match s.as_bytes().first() {
+            Some(b'-') => (true, &s[1..]),
+            Some(b'+') => (false, &s[1..]),
+            _ => (false, s),
+        };
+        if digits.is_empty() {
+            return Err(ParseAmountError::Empty);
+        }
+        if ["nan", "inf", "infinity"]
+            .iter()
+            .any(|x| digits.eq_ignore_ascii_case(x))
+        {
+            return Err(ParseAmountError::NotFinite);
+        }
+        if digits.contains(['e', 'E']) {
+            return Err(ParseAmountError::Exponent);
+        }
+        let (whole, frac) = match digits.find('.') {
+            Some(idx) => (&digits[..idx], &digits[idx + 1..]),
+            None => (digits, ""),
+        };
+        if whole.is_empty() && frac.is_empty() {
+            return Err(ParseAmountError::Empty);
+        }
+        if let Some(c) = whole
+            .chars()
+            .chain(frac.chars())
+            .find(|c| !c.is_ascii_digit())
+        {
+            return Err(ParseAmountError::InvalidCharacter(c));
+        }
+        let frac = frac.trim_end_matches('0');
+        if frac.len() > PLACES as usize {
+            return Err(ParseAmountError::TooPrecise(PLACES));
+        }
+        // pad the fractional digits out to exactly `PLACES` digits
+        let padding = std::iter::repeat_n(b'0', PLACES as usize - frac.len());
+        let units = whole
+            .bytes()
+            .chain(frac.bytes())
+            .chain(padding)
+            .try_fold(0i128, |acc, d| {
+                acc.checked_mul(10)?.checked_add(i128::from(d - b'0'))
+            })
+            .ok_or(ParseAmountError::Overflow)?;
+        Ok(Decimal::from_units(if negative { -units } else { units }))
+    }
+}
+
+impl<const PLACES: u32> fmt::Display for Decimal<PLACES> {
+    /// always prints exactly `PLACES` decimal places
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let sign = if self.is_negative() { "-" } else { "" };
+        let abs = self.units.unsigned_abs();
+        let scale = Self::SCALE as u128;
+        if PLACES == 0 {
+            write!(f, "{}{}", sign, abs)
+        } else {
+            write!(
+                f,
+                "{}{}.{:0width$}",
+                sign,
+                abs / scale,
+                abs % scale,
+                width = PLACES as usize
+            )
+        }
+    }
+}
+
+impl<const PLACES: u32> Serialize for Decimal<PLACES> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de, const PLACES: u32> Deserialize<'de> for Decimal<PLACES> {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_str(DecimalVisitor::<PLACES>)
+    }
+}
+
+struct DecimalVisitor<const PLACES: u32>;
+impl<'de, const PLACES: u32> Visitor<'de> for DecimalVisitor<PLACES> {
+    type Value = Decimal<PLACES>;
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            formatter,
+            "a decimal number with at most {} decimal places",
+            PLACES
+        )
+    }
+    fn visit_str<E>(self, value: &str) -> Result<Decimal<PLACES>, E>
+    where
+        E: DeError,
+    {
+        value
+            .parse()
+            .map_err(|err| E::custom(format!("invalid amount {:?}: {}", value, err)))
+    }
+}
@@ -0,0 +1,133 @@
+use crate::parser::accounts::{Accounts, SortKey};
+use crate::parser::amount::Amount;
+use crate::parser::store::TxStore;
+use crate::parser::transactions::Transaction;
+use anyhow::{bail, Result};
+use arrow_array::builder::{
+    BooleanBuilder, Decimal128Builder, StringBuilder, UInt16Builder, UInt32Builder,
+};
+use arrow_array::{ArrayRef, RecordBatch};
+use arrow_ipc::writer::FileWriter;
+use arrow_schema::{DataType, Field, Schema, SchemaRef};
+use std::io::Write;
+use std::sync::Arc;
+
+/// Amounts are written as decimal128 with the same 4 decimal places as `Amount`.
+/// Precision is the arrow maximum of 38 digits, which covers any realistic balance;
+/// an export with a larger amount fails.
+pub const AMOUNT_TYPE: DataType = DataType::Decimal128(38, 4);
+
+/// the largest amount units that fit in the precision of `AMOUNT_TYPE`
+const MAX_UNITS: u128 = 10u128.pow(38) - 1;
+
+/// number of transactions per record batch of the history export
+const HISTORY_BATCH_LEN: usize = 64 * 1024;
+
+/// schema of the account table: the csv columns, with amounts as exact decimals
+pub fn accounts_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new("client", DataType::UInt16, false),
+        Field::new("available", AMOUNT_TYPE, false),
+        Field::new("held", AMOUNT_TYPE, false),
+        Field::new("total", AMOUNT_TYPE, false),
+        Field::new("locked", DataType::Boolean, false),
+    ]))
+}
+
+/// schema of the transaction history: the input columns, with amounts as exact decimals
+pub fn history_schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new("type", DataType::Utf8, false),
+        Field::new("client", DataType::UInt16, false),
+        Field::new("tx", DataType::UInt32, false),
+        Field::new("amount", AMOUNT_TYPE, true),
+        Field::new("reason", DataType::UInt32, true),
+    ]))
+}
+
+impl<S: TxStore> Accounts<S> {
+    /// writes every account, ordered by client id, as an Arrow IPC file
+    pub fn write_arrow_ipc(&self, w: impl Write) -> Result<()> {
+        let accounts = self.sorted(SortKey::ClientId);
+        let mut client = UInt16Builder::with_capacity(accounts.len());
+        let mut available = amount_builder(accounts.len());
+        let mut held = amount_builder(accounts.len());
+        let mut total = amount_builder(accounts.len());
+        let mut locked = BooleanBuilder::with_capacity(accounts.len());
+        for account in accounts {
+            client.append_value(account.client_id);
+            available.append_value(units(account.avail_bal)?);
+            held.append_value(units(account.held_bal)?);
+            total.append_value(units(account.total_bal)?);
+            locked.append_value(account.locked);
+        }
+        let columns: Vec<ArrayRef> = vec![
+            Arc::new(client.finish()),
+            Arc::new(available.finish()),
+            Arc::new(held.finish()),
+            Arc::new(total.finish()),
+            Arc::new(locked.finish()),
+        ];
+        let schema = accounts_schema();
+        let mut writer = FileWriter::try_new(w, &schema)?;
+        writer.write(&RecordBatch::try_new(schema, columns)?)?;
+        writer.finish()?;
+        Ok(())
+    }
+
+    /// writes every stored transaction, ordered by client id then tx id, as an Arrow IPC file.
+    /// The history is streamed from the tx store in batches, so it need not fit in memory.
+    pub fn write_history_arrow_ipc(&self, w: impl Write) -> Result<()> {
+        let schema = history_schema();
+        let mut writer = FileWriter::try_new(w, &schema)?;
+        let mut batch = Vec::with_capacity(HISTORY_BATCH_LEN);
+        self.store.for_each(&mut |tx| {
+            batch.push(tx);
+            if batch.len() == HISTORY_BATCH_LEN {
+                writer.write(&history_batch(&schema, &batch)?)?;
+                batch.clear();
+            }
+            Ok(())
+        })?;
+        if !batch.is_empty() {
+            writer.write(&history_batch(&schema, &batch)?)?;
+        }
+        writer.finish()?;
+        Ok(())
+    }
+}
+
+/// the units of `amount`, if they fit in `AMOUNT_TYPE`
+fn units(amount: Amount) -> Result<i128> {
+    if amount.units().unsigned_abs() > MAX_UNITS {
+        bail!("amount {} has more than 38 digits", amount);
+    }
+    Ok(amount.units())
+}
+
+fn amount_builder(capacity: usize) -> Decimal128Builder {
+    Decimal128Builder::with_capacity(capacity).with_data_type(AMOUNT_TYPE)
+}
+
+fn history_batch(schema: &SchemaRef, txs: &[Transaction]) -> Result<RecordBatch> {
+    let mut transaction_type = StringBuilder::new();
+    let mut client = UInt16Builder::with_capacity(txs.len());
+    let mut tx_id = UInt32Builder::with_capacity(txs.len());
+    let mut amount = amount_builder(txs.len());
+    let mut reason = UInt32Builder::with_capacity(txs.len());
+    for tx in txs {
+        transaction_type.append_value(tx.transaction_type.as_str());
+        client.append_value(tx.client_id);
+        tx_id.append_value(tx.tx_id);
+        amount.append_option(tx.amount.map(units).transpose()?);
+        reason.append_option(tx.reason);
+    }
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(transaction_type.finish()),
+        Arc::new(client.finish()),
+        Arc::new(tx_id.finish()),
+        Arc::new(amount.finish()),
+        Arc::new(reason.finish()),
+    ];
+    Ok(RecordBatch::try_new(schema.clone(), columns)?)
+}
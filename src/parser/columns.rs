@@ -0,0 +1,111 @@
+use anyhow::{bail, Context, Result};
+use csv::ByteRecord;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io::Read;
+
+/// Names of the transaction fields, as used in the canonical csv header.
+pub const FIELDS: [&str; 5] = ["type", "client", "tx", "amount", "reason"];
+/// Fields every input must have a column for. Without an amount column
+/// only disputes, resolves, chargebacks, locks, unlocks and closes parse;
+/// without a reason column, no adjustments.
+const REQUIRED_FIELDS: [&str; 3] = ["type", "client", "tx"];
+
+/// Which input columns hold which transaction fields.
+///
+/// By default a column is matched by its header, ignoring case and surrounding whitespace.
+/// `headers` renames the header expected for a field, e.g. `tx` → `id`.
+/// For input without a header row, `positions` names the field in each column instead.
+/// Columns that match no field are ignored.
+///
+/// Can be read from a JSON config file, e.g.
+/// `{"headers": {"type": "txn_type", "client": "customer_id", "tx": "id"}}`
+/// or `{"positions": ["client", "tx", "type", "amount"]}`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ColumnMapping {
+    /// header of the column holding each field, by field name
+    pub headers: HashMap<String, String>,
+    /// the input has no header row; the field in each column, in order.
+    /// Names that are not a field, like `_`, skip the column.
+    pub positions: Option<Vec<String>>,
+}
+
+impl ColumnMapping {
+    /// reads a mapping from a JSON config file
+    pub fn from_json(r: impl Read) -> Result<Self> {
+        let mut mapping: ColumnMapping =
+            serde_json::from_reader(r).context("invalid column mapping")?;
+        for (field, header) in std::mem::take(&mut mapping.headers) {
+            mapping.rename(&field, &header)?;
+        }
+        Ok(mapping)
+    }
+
+    /// expects the column of `field` (in any case) under `header`
+    pub fn rename(&mut self, field: &str, header: &str) -> Result<()> {
+        let field = check_field(field)?;
+        self.headers.insert(field.to_string(), header.to_string());
+        Ok(())
+    }
+
+    /// whether the input starts with a header row
+    pub fn has_headers(&self) -> bool {
+        self.positions.is_none()
+    }
+
+    /// the canonical header (`FIELDS`, or empty for ignored columns) of input with `headers`,
+    /// or of headerless input if `headers` is `None`
+    pub fn canonical_headers(&self, headers: Option<&ByteRecord>) -> Result<ByteRecord> {
+        let canonical: ByteRecord = match (&self.positions, headers) {
+            (Some(positions), _) => positions
+                .iter()
+                .map(|name| field_named(name).unwrap_or(""))
+                .collect(),
+            (None, Some(headers)) => headers
+                .iter()
+                .map(|header| {
+                    let header = String::from_utf8_lossy(header);
+                    FIELDS
+                        .iter()
+                        .copied()
+                        .find(|field| self.header_of(field).eq_ignore_ascii_case(header.trim()))
+                        .unwrap_or("")
+                })
+                .collect(),
+            (None, None) => bail!("input has no header row and no column positions were given"),
+        };
+        for field in REQUIRED_FIELDS {
+            if !canonical.iter().any(|name| name == field.as_bytes()) {
+                match &self.positions {
+                    Some(_) => bail!("no column position given for {}", field),
+                    None => bail!("no {:?} column in the header", self.header_of(field)),
+                }
+            }
+        }
+        Ok(canonical)
+    }
+
+    /// the header expected for `field`
+    fn header_of<'a>(&'a self, field: &'a str) -> &'a str {
+        self.headers.get(field).map_or(field, String::as_str)
+    }
+}
+
+fn field_named(name: &str) -> Option<&'static str> {
+    FIELDS
+        .iter()
+        .copied()
+        .find(|field| field.eq_ignore_ascii_case(name.trim()))
+}
+
+fn check_field(name: &str) -> Result<&'static str> {
+    match field_named(name) {
+        Some(field) => Ok(field),
+        None => bail!(
+            "unknown field {:?}, expected one of: {}",
+            name,
+            FIELDS.join(", ")
+        ),
+    }
+}
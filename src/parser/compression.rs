@@ -0,0 +1,167 @@
+use anyhow::{bail, Result};
+use std::io::{self, Cursor, Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+/// Compression formats of inputs and outputs.
+/// Support for each is built in by the `gzip` and `zstd` features.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip,
+    Zstd,
+}
+
+/// magic bytes at the start of a gzip member
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+/// magic bytes at the start of a zstd frame
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+impl Compression {
+    /// the compression implied by the extension of `path`: `.gz` or `.zst`
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "gz" | "gzip" => Some(Compression::Gzip),
+            "zst" | "zstd" => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+
+    /// `path` without its compression extension, e.g. `accounts.csv` for `accounts.csv.gz`
+    pub fn strip_extension(path: &Path) -> &Path {
+        match (Self::from_path(path), path.file_stem()) {
+            (Some(_), Some(stem)) => Path::new(stem),
+            _ => path,
+        }
+    }
+
+    /// the compression whose magic bytes start `head`
+    pub fn detect(head: &[u8]) -> Self {
+        if head.starts_with(&GZIP_MAGIC) {
+            Compression::Gzip
+        } else if head.starts_with(&ZSTD_MAGIC) {
+            Compression::Zstd
+        } else {
+            Compression::None
+        }
+    }
+
+    /// decompresses `r` as it is read
+    pub fn decoder<'a>(self, r: impl Read + 'a) -> Result<Box<dyn Read + 'a>> {
+        Ok(match self {
+            Compression::None => Box::new(r),
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => Box::new(flate2::read::MultiGzDecoder::new(r)),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => Box::new(zstd::Decoder::new(r)?),
+            #[cfg(not(feature = "gzip"))]
+            Compression::Gzip => bail!("built without the gzip feature"),
+            #[cfg(not(feature = "zstd"))]
+            Compression::Zstd => bail!("built without the zstd feature"),
+        })
+    }
+
+    /// compresses everything written to `w`
+    pub fn encoder<W: Write>(self, w: W) -> Result<Encoder<W>> {
+        Ok(Encoder(match self {
+            Compression::None => Inner::Plain(w),
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => Inner::Gzip(flate2::write::GzEncoder::new(
+                w,
+                flate2::Compression::default(),
+            )),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => Inner::Zstd(zstd::Encoder::new(w, 0)?),
+            #[cfg(not(feature = "gzip"))]
+            Compression::Gzip => bail!("built without the gzip feature"),
+            #[cfg(not(feature = "zstd"))]
+            Compression::Zstd => bail!("built without the zstd feature"),
+        }))
+    }
+}
+
+impl FromStr for Compression {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "none" => Ok(Compression::None),
+            "gzip" | "gz" => Ok(Compression::Gzip),
+            "zstd" | "zst" => Ok(Compression::Zstd),
+            _ => bail!("unknown compression {:?}, expected none, gzip or zstd", s),
+        }
+    }
+}
+
+/// decompresses `r` as it is read, detecting its compression from its magic bytes
+pub fn decompress<'a>(r: impl Read + 'a) -> Result<Box<dyn Read + 'a>> {
+    let (head, r) = peek(r, ZSTD_MAGIC.len())?;
+    Compression::detect(head.get_ref()).decoder(head.chain(r))
+}
+
+/// reads up to `len` bytes from the start of `r`, and returns them with the rest of `r`
+fn peek<R: Read>(mut r: R, len: usize) -> io::Result<(Cursor<Vec<u8>>, R)> {
+    let mut head = Vec::with_capacity(len);
+    (&mut r).take(len as u64).read_to_end(&mut head)?;
+    Ok((Cursor::new(head), r))
+}
+
+/// Compresses what is written to it, see [`Compression::encoder`].
+/// The compressed stream is finished by [`Encoder::finish`], or else when dropped,
+/// in which case errors are lost.
+pub struct Encoder<W: Write>(Inner<W>);
+
+enum Inner<W: Write> {
+    Plain(W),
+    #[cfg(feature = "gzip")]
+    Gzip(flate2::write::GzEncoder<W>),
+    #[cfg(feature = "zstd")]
+    Zstd(zstd::Encoder<'static, W>),
+}
+
+impl<W: Write> Encoder<W> {
+    /// writes the end of the compressed stream and flushes it
+    pub fn finish(mut self) -> io::Result<()> {
+        self.try_finish()
+    }
+
+    fn try_finish(&mut self) -> io::Result<()> {
+        match &mut self.0 {
+            Inner::Plain(w) => w.flush(),
+            #[cfg(feature = "gzip")]
+            Inner::Gzip(w) => w.try_finish(),
+            #[cfg(feature = "zstd")]
+            Inner::Zstd(w) => w.do_finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut self.0 {
+            Inner::Plain(w) => w.write(buf),
+            #[cfg(feature = "gzip")]
+            Inner::Gzip(w) => w.write(buf),
+            #[cfg(feature = "zstd")]
+            Inner::Zstd(w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.0 {
+            Inner::Plain(w) => w.flush(),
+            #[cfg(feature = "gzip")]
+            Inner::Gzip(w) => w.flush(),
+            #[cfg(feature = "zstd")]
+            Inner::Zstd(w) => w.flush(),
+        }
+    }
+}
+
+impl<W: Write> Drop for Encoder<W> {
+    fn drop(&mut self) {
+        let _ = self.try_finish();
+    }
+}
@@ -0,0 +1,238 @@
+use crate::parser::accounts::{Account, Applied, TxRejection};
+use crate::parser::amount::Amount;
+use crate::parser::transactions::Transaction;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+/// What happened to an account.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    /// the account was created by its first transaction, applied or not
+    Opened,
+    /// a deposit added to the available and total balances
+    Credited,
+    /// a withdrawal took from the available and total balances
+    Debited,
+    /// a dispute moved (part of) a transaction's amount from the available to the held balance
+    Held,
+    /// a resolve moved a disputed amount back from the held to the available balance
+    Released,
+    /// a chargeback removed a disputed amount from the held and total balances
+    ChargedBack,
+    /// the account was frozen, by a chargeback or an admin
+    Locked,
+    /// an admin unfroze the account
+    Unlocked,
+    /// an admin corrected the available and total balances
+    Adjusted,
+    /// an admin closed the account for good
+    Closed,
+    /// a rejected deposit, withdrawal or admin operation used up its tx id;
+    /// balances are unchanged
+    Claimed,
+}
+
+/// The balances of an account before or after an event.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct Balances {
+    pub available: Amount,
+    pub held: Amount,
+    pub total: Amount,
+    pub locked: bool,
+}
+
+impl Balances {
+    pub fn of(account: &Account) -> Self {
+        Balances {
+            available: account.avail_bal,
+            held: account.held_bal,
+            total: account.total_bal,
+            locked: account.locked,
+        }
+    }
+}
+
+impl fmt::Display for Balances {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "available {}, held {}, total {}, {}",
+            self.available,
+            self.held,
+            self.total,
+            if self.locked { "locked" } else { "unlocked" }
+        )
+    }
+}
+
+/// One entry of the audit journal: a change to an account and the transaction that caused it.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Event {
+    pub event: EventKind,
+    pub client: u16,
+    pub tx: u32,
+    /// the (unsigned) amount of the deposit or withdrawal, or the part of the disputed
+    /// transaction that moved, or the signed amount of an adjustment; `None` for the other events
+    pub amount: Option<Amount>,
+    /// the reason code of an admin operation
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<u32>,
+    pub before: Balances,
+    pub after: Balances,
+}
+
+/// Receives the journal of every change made by `Accounts::process_transaction`.
+/// Events of one transaction arrive together, in order. With several workers,
+/// events of different clients may interleave, but each client's stay in order.
+pub trait JournalSink: Send {
+    fn record(&mut self, events: &[Event]) -> Result<()>;
+
+    /// writes out anything buffered
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl<F: FnMut(&[Event]) -> Result<()> + Send> JournalSink for F {
+    fn record(&mut self, events: &[Event]) -> Result<()> {
+        self(events)
+    }
+}
+
+/// Writes the journal as JSON Lines, one event per line.
+pub struct JournalWriter<W: Write + Send> {
+    w: W,
+}
+
+impl<W: Write + Send> JournalWriter<W> {
+    pub fn new(w: W) -> Self {
+        JournalWriter { w }
+    }
+}
+
+impl<W: Write + Send> JournalSink for JournalWriter<W> {
+    fn record(&mut self, events: &[Event]) -> Result<()> {
+        for event in events {
+            serde_json::to_writer(&mut self.w, event)?;
+            self.w.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.w.flush()?;
+        Ok(())
+    }
+}
+
+/// A shared handle to a [`JournalSink`]; clones write to the same sink.
+#[derive(Clone)]
+pub struct Journal(Arc<Mutex<dyn JournalSink>>);
+
+impl Journal {
+    pub fn new(sink: impl JournalSink + 'static) -> Self {
+        Journal(Arc::new(Mutex::new(sink)))
+    }
+
+    pub fn record(&self, events: &[Event]) -> Result<()> {
+        self.lock().record(events)
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        self.lock().flush()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, dyn JournalSink + 'static> {
+        // a sink that panicked mid-write is still the best record there is
+        self.0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl fmt::Debug for Journal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Journal(..)")
+    }
+}
+
+/// the events of `t`, given the account before (`None` if it did not exist) and after it,
+/// and the outcome
+pub(crate) fn events(
+    t: &Transaction,
+    before: Option<Balances>,
+    after: Balances,
+    result: &Result<Applied, TxRejection>,
+) -> Vec<Event> {
+    let (client, tx) = (t.client_id, t.tx_id);
+    let reason = if t.transaction_type.is_admin() {
+        t.reason
+    } else {
+        None
+    };
+    let event = |event, amount, before, after| Event {
+        event,
+        client,
+        tx,
+        amount,
+        reason,
+        before,
+        after,
+    };
+    let mut events = vec![];
+    let before = match before {
+        Some(before) => before,
+        None => {
+            let opened = Balances::default();
+            events.push(event(EventKind::Opened, None, opened, opened));
+            opened
+        }
+    };
+    match *result {
+        Ok(Applied::Deposit(amount)) => {
+            events.push(event(EventKind::Credited, Some(amount), before, after))
+        }
+        Ok(Applied::Withdrawal(amount)) => {
+            events.push(event(EventKind::Debited, Some(amount), before, after))
+        }
+        Ok(Applied::Dispute(amount)) => {
+            events.push(event(EventKind::Held, Some(amount), before, after))
+        }
+        Ok(Applied::Resolve(amount)) => {
+            events.push(event(EventKind::Released, Some(amount), before, after))
+        }
+        Ok(Applied::Chargeback(amount)) => {
+            let charged_back = Balances {
+                locked: before.locked,
+                ..after
+            };
+            events.push(event(
+                EventKind::ChargedBack,
+                Some(amount),
+                before,
+                charged_back,
+            ));
+            if after.locked && !before.locked {
+                events.push(event(EventKind::Locked, None, charged_back, after));
+            }
+        }
+        Ok(Applied::Adjustment(amount)) => {
+            events.push(event(EventKind::Adjusted, Some(amount), before, after))
+        }
+        Ok(Applied::Lock) => events.push(event(EventKind::Locked, None, before, after)),
+        Ok(Applied::Unlock) => events.push(event(EventKind::Unlocked, None, before, after)),
+        Ok(Applied::Close) => events.push(event(EventKind::Closed, None, before, after)),
+        // rejected before its tx id was claimed
+        Err(TxRejection::DuplicateTx { .. }) | Err(TxRejection::ForeignTx { .. }) => {}
+        Err(_) => {
+            if t.transaction_type.claims_tx_id() {
+                events.push(event(EventKind::Claimed, None, before, after));
+            }
+        }
+    }
+    events
+}
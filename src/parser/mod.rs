@@ -1,5 +1,18 @@
 pub mod accounts;
+pub mod amount;
+#[cfg(feature = "arrow")]
+pub mod columnar;
+pub mod columns;
+pub mod compression;
+pub mod journal;
+pub mod reader;
+pub mod rebuild;
+pub mod report;
+pub mod snapshot;
+pub mod stats;
+pub mod store;
 pub mod transactions;
+pub mod verify;
 
 #[cfg(test)]
 mod parser_tests;
@@ -1,9 +1,25 @@
-use crate::parser::accounts::{Account, Accounts};
-use crate::parser::transactions::{FloatingPoint, FloatingPointWrap, Transaction, TransactionType};
+use crate::ledger::{AsOf, Ledger};
+use crate::parser::accounts::{
+    Account, Accounts, AdminPolicy, Applied, DisputePolicy, FatalError, OutputFormat, SortKey,
+    TxIdScope, TxRejection,
+};
+use crate::parser::amount::{Amount, ParseAmountError};
+use crate::parser::columns::ColumnMapping;
+use crate::parser::journal::{Balances, Event, EventKind, Journal};
+use crate::parser::reader::{
+    detect_delimiter, ParseMode, ReaderOptions, Row, RowError, TransactionReader,
+};
+use crate::parser::rebuild::{read_accounts_csv, Mismatch};
+use crate::parser::report::{Quarantine, RejectionReport};
+use crate::parser::stats::Summary;
+use crate::parser::store::{DiskTxStore, TxStore};
+use crate::parser::transactions::{Transaction, TransactionType};
+use crate::parser::verify::Violation;
 use anyhow::Result;
 use csv::{ReaderBuilder, Trim};
-use fixed_macro::fixed;
 use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+use std::sync::{Arc, Mutex};
 
 const DATA_1: &str = r#"
        type, client ,tx , amount
@@ -22,55 +38,64 @@ const DATA_1_EXPECTED_TXS: [Transaction; 9] = [
         transaction_type: TransactionType::Deposit,
         client_id: 1,
         tx_id: 1,
-        amount: Some(FloatingPointWrap(fixed!(1.0: I113F15))),
+        amount: Some(Amount::from_units(1_0000)),
+        reason: None,
     },
     Transaction {
         transaction_type: TransactionType::Deposit,
         client_id: 2,
         tx_id: 2,
-        amount: Some(FloatingPointWrap(fixed!(2.0: I113F15))),
+        amount: Some(Amount::from_units(2_0000)),
+        reason: None,
     },
     Transaction {
         transaction_type: TransactionType::Deposit,
         client_id: 1,
         tx_id: 3,
-        amount: Some(FloatingPointWrap(fixed!(2.0: I113F15))),
+        amount: Some(Amount::from_units(2_0000)),
+        reason: None,
     },
     Transaction {
         transaction_type: TransactionType::Withdrawal,
         client_id: 1,
         tx_id: 4,
-        amount: Some(FloatingPointWrap(fixed!(1.5: I113F15))),
+        amount: Some(Amount::from_units(1_5000)),
+        reason: None,
     },
     Transaction {
         transaction_type: TransactionType::Withdrawal,
         client_id: 2,
         tx_id: 5,
-        amount: Some(FloatingPointWrap(fixed!(3.0: I113F15))),
+        amount: Some(Amount::from_units(3_0000)),
+        reason: None,
     },
     Transaction {
         transaction_type: TransactionType::Dispute,
         client_id: 1,
         tx_id: 1,
         amount: None,
+        reason: None,
     },
     Transaction {
         transaction_type: TransactionType::Resolve,
         client_id: 1,
         tx_id: 1,
         amount: None,
+        reason: None,
     },
     Transaction {
         transaction_type: TransactionType::Dispute,
         client_id: 2,
         tx_id: 2,
         amount: None,
+        reason: None,
     },
     Transaction {
         transaction_type: TransactionType::Chargeback,
         client_id: 2,
         tx_id: 2,
         amount: None,
+        reason: None,
     },
 ];
 const DATA_1_EXPECTED: [&str; 3] = [
@@ -113,6 +138,7 @@ const DATA_5_EXPECTED: [&str; 2] = [
     "1,5.0000,0.0000,5.0000,true",
 ];
 
+// the last deposit reuses tx id 1, so it is rejected as a duplicate
 const DATA_6: &str = r#"
        type, client ,tx , amount
        deposit,  1, 1,    5
@@ -123,9 +149,10 @@ const DATA_6: &str = r#"
 
 const DATA_6_EXPECTED: [&str; 2] = [
     "client,available,held,total,locked",
-    "1,7.0000,0.0000,7.0000,false",
+    "1,2.0000,0.0000,2.0000,false",
 ];
 
+// the last deposit reuses tx id 1, so it is rejected as a duplicate
 const DATA_7: &str = r#"
        type, client ,tx , amount
        deposit,  1, 1,    5
@@ -137,7 +164,7 @@ const DATA_7: &str = r#"
 
 const DATA_7_EXPECTED: [&str; 2] = [
     "client,available,held,total,locked",
-    "1,7.0000,0.0000,7.0000,false",
+    "1,2.0000,0.0000,2.0000,false",
 ];
 
 const DATA_8: &str = r#"
@@ -154,20 +181,31 @@ const DATA_8_EXPECTED: [&str; 2] = [
     "1,-3.0000,0.0000,-3.0000,true",
 ];
 
-/// helper function to test that the processed account's serialized results matches the account's actual value
-fn test_data(data: &str, data_expected: Vec<&str>) -> Result<()> {
+/// helper function to process `data` into `accounts` and serialize the result
+fn process_data<S: TxStore>(data: &str, mut accounts: Accounts<S>) -> Result<String> {
     let mut rdr = ReaderBuilder::new()
         .trim(Trim::All)
         .flexible(true)
         .delimiter(b',')
         .from_reader(data.as_bytes());
-    let mut accounts = Accounts::new();
     for result in rdr.deserialize() {
-        accounts.process_transaction(&result?);
+        let _ = accounts.process_transaction(&result?);
     }
     let mut serialized_result = vec![];
     accounts.serialize_to_writer(&mut serialized_result)?;
-    let serialized_result_utf8 = String::from_utf8(serialized_result)?;
+    Ok(String::from_utf8(serialized_result)?)
+}
+
+/// helper function returning the ledger of DATA_1, which other inputs are compared to
+fn data_1_ledger() -> Result<Ledger> {
+    let mut ledger = Ledger::new();
+    ledger.process_csv(DATA_1.trim().as_bytes(), |_, _| Ok(()))?;
+    Ok(ledger)
+}
+
+/// helper function to test that the processed account's serialized results matches the account's actual value
+fn test_data(data: &str, data_expected: Vec<&str>) -> Result<()> {
+    let serialized_result_utf8 = process_data(data, Accounts::new())?;
     let lines_expected: HashSet<String> = data_expected.iter().map(|x| x.to_string()).collect();
     assert_eq!(
         lines_expected,
@@ -179,8 +217,8 @@ fn test_data(data: &str, data_expected: Vec<&str>) -> Result<()> {
     Ok(())
 }
 
-/// tests that (1) transactions after resolve tx do effect final result
-/// and        (2) deposit returns held funds to avail funds
+/// tests that (1) resolving a disputed deposit returns its held funds to avail funds
+/// and        (2) a resolve of an undisputed tx, and a deposit reusing a tx id, are ignored
 #[test]
 pub fn test_deposit_resolve() -> Result<()> {
     test_data(DATA_7, DATA_7_EXPECTED.to_vec())
@@ -192,8 +230,8 @@ pub fn test_deposit_chargeback() -> Result<()> {
     test_data(DATA_8, DATA_8_EXPECTED.to_vec())
 }
 
-/// tests that (1) transactions after chargeback do not effect final result
-/// and        (2) chargeback returns withdrawn funds
+/// tests that (1) resolving a disputed withdrawal leaves it withdrawn
+/// and        (2) a deposit reusing a tx id after the resolve is ignored
 #[test]
 pub fn test_withdrawal_resolve() -> Result<()> {
     test_data(DATA_6, DATA_6_EXPECTED.to_vec())
@@ -271,38 +309,43 @@ pub fn test_process_txs() -> Result<()> {
     let mut accounts = Accounts::new();
     for result in rdr.deserialize() {
         let finished = result?;
-        accounts.process_transaction(&finished);
+        let _ = accounts.process_transaction(&finished);
         //println!("tx: {:?}\naccount: {:?}\n\n", &finished, accounts);
     }
     let mut expected_accounts = Accounts::new();
-    let mut expected_hm_account_1 = HashMap::new();
-    let mut expected_hm_account_2 = HashMap::new();
-    expected_hm_account_1.insert(DATA_1_EXPECTED_TXS[0].tx_id, DATA_1_EXPECTED_TXS[0]);
-    expected_hm_account_1.insert(DATA_1_EXPECTED_TXS[2].tx_id, DATA_1_EXPECTED_TXS[2]);
-    expected_hm_account_1.insert(DATA_1_EXPECTED_TXS[3].tx_id, DATA_1_EXPECTED_TXS[3]);
-    expected_hm_account_2.insert(DATA_1_EXPECTED_TXS[1].tx_id, DATA_1_EXPECTED_TXS[1]);
+    for i in [0, 2, 3, 1] {
+        expected_accounts.store.insert(DATA_1_EXPECTED_TXS[i])?;
+    }
+    // every deposit and withdrawal uses up its tx id, even the rejected withdrawal
+    for tx in &DATA_1_EXPECTED_TXS[..5] {
+        expected_accounts.seen_tx_ids.insert(tx.client_id, tx.tx_id);
+    }
     expected_accounts.state.insert(
         1,
         Account {
             client_id: 1,
-            avail_bal: FloatingPoint::from_num(1.5),
-            held_bal: FloatingPoint::from_num(0),
-            total_bal: FloatingPoint::from_num(1.5),
+            avail_bal: Amount::from_units(1_5000),
+            held_bal: Amount::ZERO,
+            total_bal: Amount::from_units(1_5000),
             locked: false,
-            transactions: expected_hm_account_1,
-            disputes: HashSet::new(),
+            disputes: HashMap::new(),
+            charged_back_txs: HashMap::new(),
+            charged_back: Amount::ZERO,
+            closed: false,
         },
     );
     expected_accounts.state.insert(
         2,
         Account {
             client_id: 2,
-            avail_bal: FloatingPoint::from_num(0),
-            held_bal: FloatingPoint::from_num(0),
-            total_bal: FloatingPoint::from_num(0),
+            avail_bal: Amount::ZERO,
+            held_bal: Amount::ZERO,
+            total_bal: Amount::ZERO,
             locked: true,
-            transactions: expected_hm_account_2,
-            disputes: HashSet::new(),
+            disputes: HashMap::new(),
+            charged_back_txs: HashMap::from([(2, Amount::from_units(2_0000))]),
+            charged_back: Amount::from_units(2_0000),
+            closed: false,
         },
     );
     assert_eq!(accounts, expected_accounts);
@@ -315,3 +358,1582 @@ pub fn test_process_txs() -> Result<()> {
 pub fn test_serialize() -> Result<()> {
     test_data(DATA_1, DATA_1_EXPECTED.to_vec())
 }
+
+/// check that every ignored transaction reports why it was ignored
+#[test]
+pub fn test_rejections() -> Result<()> {
+    let mut rdr = ReaderBuilder::new()
+        .trim(Trim::All)
+        .flexible(true)
+        .delimiter(b',')
+        .from_reader(DATA_8.as_bytes());
+    let mut accounts = Accounts::new();
+    let results = rdr
+        .deserialize()
+        .map(|result| Ok(accounts.process_transaction(&result?)))
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        results,
+        vec![
+            Ok(Applied::Deposit(Amount::from_units(5_0000))),
+            Ok(Applied::Withdrawal(Amount::from_units(3_0000))),
+            Ok(Applied::Dispute(Amount::from_units(5_0000))),
+            Ok(Applied::Chargeback(Amount::from_units(5_0000))),
+            Err(TxRejection::DuplicateTx {
+                client_id: 1,
+                tx_id: 1
+            }),
+            Err(TxRejection::AccountLocked {
+                client_id: 1,
+                tx_id: 8
+            }),
+        ]
+    );
+
+    let mut accounts = Accounts::new();
+    let withdrawal = Transaction {
+        transaction_type: TransactionType::Withdrawal,
+        client_id: 3,
+        tx_id: 1,
+        amount: Some(Amount::from_units(1_0000)),
+        reason: None,
+    };
+    let resolve = Transaction {
+        transaction_type: TransactionType::Resolve,
+        client_id: 3,
+        tx_id: 1,
+        amount: None,
+        reason: None,
+    };
+    assert_eq!(
+        accounts.process_transaction(&withdrawal),
+        Err(TxRejection::InsufficientFunds {
+            client_id: 3,
+            tx_id: 1
+        })
+    );
+    assert_eq!(
+        accounts.process_transaction(&resolve),
+        Err(TxRejection::UnknownTx {
+            client_id: 3,
+            tx_id: 1
+        })
+    );
+    Ok(())
+}
+
+/// check that the rejection report records the line, the record as written and the reason
+#[test]
+pub fn test_rejection_report() -> Result<()> {
+    let data = format!(
+        "{}\r\n# note\ndeposit; 1;5;\"1,5\n\"",
+        DATA_3.replace(',', ";")
+    );
+    let reader = TransactionReader::with_options(
+        data.as_bytes(),
+        &ReaderOptions {
+            delimiter: Some(b';'),
+            comment: Some(b'#'),
+            ..ReaderOptions::default()
+        },
+    )?;
+    let mut accounts = Accounts::new();
+    let mut serialized_report = vec![];
+    {
+        let mut report = RejectionReport::new(&mut serialized_report);
+        report.set_input("data_3.csv");
+        for row in reader {
+            let row = row?;
+            match &row.transaction {
+                Ok(tx) => {
+                    if let Err(rejection) = accounts.process_transaction(tx) {
+                        report.record(&row, &rejection)?;
+                    }
+                }
+                Err(err) => report.record(&row, err)?,
+            }
+        }
+        report.flush()?;
+    }
+    // records are copied as written, without the comment line before them
+    assert_eq!(
+        String::from_utf8(serialized_report)?,
+        "input,line,record,reason\n\
+         data_3.csv,3,       withdrawal;  1;4;    1.5;;;;,\
+         tx 4 would overdraw the available balance of client 1\n\
+         data_3.csv,5,\"deposit; 1;5;\"\"1,5\n\"\"\",\
+         \"invalid amount: \"\"1,5\"\": amount contains invalid character ','\"\n"
+    );
+    Ok(())
+}
+
+/// check that amounts are parsed exactly, and imprecise, non-decimal or negative amounts
+/// are rejected
+#[test]
+pub fn test_parse_amount() -> Result<()> {
+    assert_eq!("0.0001".parse::<Amount>()?, Amount::from_units(1));
+    assert_eq!("-2.5".parse::<Amount>()?, Amount::from_units(-2_5000));
+    assert_eq!(".5".parse::<Amount>()?, Amount::from_units(5000));
+    assert_eq!("3.10000".parse::<Amount>()?, Amount::from_units(3_1000));
+    assert_eq!("0.0001".parse::<Amount>()?.to_string(), "0.0001");
+    assert_eq!("-0.1".parse::<Amount>()?.to_string(), "-0.1000");
+    assert_eq!(
+        "0.00001".parse::<Amount>(),
+        Err(ParseAmountError::TooPrecise(4))
+    );
+    assert_eq!("1e3".parse::<Amount>(), Err(ParseAmountError::Exponent));
+    assert_eq!("NaN".parse::<Amount>(), Err(ParseAmountError::NotFinite));
+    assert_eq!("-inf".parse::<Amount>(), Err(ParseAmountError::NotFinite));
+    assert_eq!(
+        "1.2.3".parse::<Amount>(),
+        Err(ParseAmountError::InvalidCharacter('.'))
+    );
+    assert_eq!(".".parse::<Amount>(), Err(ParseAmountError::Empty));
+    assert_eq!(
+        "999999999999999999999999999999999999999".parse::<Amount>(),
+        Err(ParseAmountError::Overflow)
+    );
+
+    // only adjustments are signed
+    let data = "type,client,tx,amount,reason\n\
+                deposit,1,1,-5\n\
+                withdrawal,1,2,-5\n\
+                deposit,1,3,0\n\
+                adjust,1,4,-5,1\n";
+    let valid: Vec<bool> = TransactionReader::new(data.as_bytes())?
+        .map(|row| row.map(|row| row.transaction.is_ok()))
+        .collect::<Result<_>>()?;
+    assert_eq!(valid, vec![false, false, true, true]);
+    let withdrawal = Transaction {
+        transaction_type: TransactionType::Withdrawal,
+        client_id: 1,
+        tx_id: 1,
+        amount: Some(Amount::from_units(-5_0000)),
+        reason: None,
+    };
+    assert_eq!(
+        Accounts::new().process_transaction(&withdrawal),
+        Err(TxRejection::NegativeAmount {
+            client_id: 1,
+            tx_id: 1
+        })
+    );
+    Ok(())
+}
+
+/// check that the on-disk tx store gives the same results as the in-memory store
+#[test]
+pub fn test_disk_tx_store() -> Result<()> {
+    let path = std::env::temp_dir().join(format!("toy_txs_store_{}.redb", std::process::id()));
+    for data in [DATA_1, DATA_3, DATA_4, DATA_5, DATA_6, DATA_7, DATA_8] {
+        let mut expected: Vec<String> = process_data(data, Accounts::new())?
+            .lines()
+            .map(|x| x.to_string())
+            .collect();
+        let mut actual: Vec<String> =
+            process_data(data, Accounts::with_store(DiskTxStore::create(&path)?))?
+                .lines()
+                .map(|x| x.to_string())
+                .collect();
+        expected.sort();
+        actual.sort();
+        assert_eq!(expected, actual);
+    }
+    let mut store = DiskTxStore::create(&path)?;
+    store.insert(DATA_1_EXPECTED_TXS[0])?;
+    store.flush()?;
+    assert_eq!(store.get(1, 1)?, Some(DATA_1_EXPECTED_TXS[0]));
+    assert_eq!(store.get(2, 1)?, None);
+    std::fs::remove_file(&path)?;
+    Ok(())
+}
+
+/// check that the ledger reports rejected rows and stops at the first malformed row
+#[test]
+pub fn test_ledger_process_csv() -> Result<()> {
+    let data = r#"
+       type, client ,tx , amount
+       deposit, 1, 1, 1.0
+       resolve, 1, 1,
+       deposit, 1, 2,
+       deposit, 1, 3, 1.0"#;
+    let mut ledger = Ledger::new();
+    let mut rejected = vec![];
+    let result = ledger.process_csv(data.as_bytes(), |row, reason| {
+        rejected.push((row.line(), reason.to_string()));
+        Ok(())
+    });
+    assert!(result.is_err());
+    assert_eq!(
+        rejected,
+        vec![
+            (4, "tx 1 of client 1 is not under dispute".to_string()),
+            (5, "amount does not match transaction type".to_string()),
+        ]
+    );
+    assert_eq!(
+        ledger.account(1).map(|account| account.total_bal),
+        Some(Amount::from_units(1_0000))
+    );
+    Ok(())
+}
+
+/// check that output is ordered by client id by default, and by the requested key otherwise
+#[test]
+pub fn test_sorted_output() -> Result<()> {
+    let data = r#"
+       type, client ,tx , amount
+       deposit, 3, 1, 1.0
+       deposit, 1, 2, 3.0
+       deposit, 2, 3, 2.0
+       dispute, 2, 3,
+       chargeback, 2, 3,"#;
+    assert_eq!(
+        process_data(data, Accounts::new())?,
+        "client,available,held,total,locked\n\
+         1,3.0000,0.0000,3.0000,false\n\
+         2,0.0000,0.0000,0.0000,true\n\
+         3,1.0000,0.0000,1.0000,false\n"
+    );
+    let mut ledger = Ledger::new();
+    ledger.process_csv(data.as_bytes(), |_, _| Ok(()))?;
+    let client_ids = |key| {
+        ledger
+            .accounts()
+            .sorted(key)
+            .iter()
+            .map(|account| account.client_id)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(client_ids(SortKey::Total), vec![2, 3, 1]);
+    assert_eq!(client_ids(SortKey::Available), vec![2, 3, 1]);
+    assert_eq!(client_ids(SortKey::LockedFirst), vec![2, 1, 3]);
+    assert!("balance".parse::<SortKey>().is_err());
+    Ok(())
+}
+
+/// check that a snapshot restores open disputes, so a later run can resolve them
+#[test]
+pub fn test_snapshot() -> Result<()> {
+    let mut ledger = Ledger::new();
+    ledger.process_csv(DATA_4.as_bytes(), |_, _| Ok(()))?;
+    let mut snapshot = vec![];
+    ledger.save_snapshot(&mut snapshot)?;
+    let restored = Ledger::new().load_snapshot(snapshot.as_slice())?;
+    assert_eq!(restored, ledger);
+
+    let path = std::env::temp_dir().join(format!("toy_txs_snapshot_{}.redb", std::process::id()));
+    let mut restored =
+        Ledger::with_store(DiskTxStore::create(&path)?).load_snapshot(snapshot.as_slice())?;
+    let next_day = r#"
+       type, client ,tx , amount
+       resolve, 1, 1,
+       resolve, 1, 2,"#;
+    restored.process_csv(next_day.as_bytes(), |_, reason| {
+        panic!("unexpected rejection: {}", reason)
+    })?;
+    let mut serialized_result = vec![];
+    restored.write_csv(&mut serialized_result)?;
+    assert_eq!(
+        String::from_utf8(serialized_result)?,
+        "client,available,held,total,locked\n1,2.0000,0.0000,2.0000,false\n"
+    );
+    drop(restored);
+    std::fs::remove_file(&path)?;
+
+    let mut wrong_version = String::from_utf8(snapshot)?;
+    wrong_version = wrong_version.replacen("\"version\":1", "\"version\":2", 1);
+    assert!(Ledger::new()
+        .load_snapshot(wrong_version.as_bytes())
+        .is_err());
+    Ok(())
+}
+
+/// check that the dispute policy decides which transactions may be disputed
+#[test]
+pub fn test_dispute_policy() -> Result<()> {
+    let deposits_only = Accounts::new().with_dispute_policy(DisputePolicy::DepositsOnly);
+    assert_eq!(
+        process_data(DATA_4, deposits_only)?,
+        "client,available,held,total,locked\n1,-3.0000,5.0000,2.0000,false\n"
+    );
+
+    // only large transactions may be disputed
+    let large_only = DisputePolicy::Custom(Arc::new(|tx: &Transaction| {
+        tx.amount
+            .is_some_and(|amount| amount >= Amount::from_units(4_0000))
+    }));
+    let mut accounts = Accounts::new().with_dispute_policy(large_only);
+    let mut rdr = ReaderBuilder::new()
+        .trim(Trim::All)
+        .flexible(true)
+        .from_reader(DATA_4.as_bytes());
+    let results = rdr
+        .deserialize()
+        .map(|result| Ok(accounts.process_transaction(&result?)))
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        results[2],
+        Err(TxRejection::NotDisputable {
+            client_id: 1,
+            tx_id: 2
+        })
+    );
+    assert_eq!(results[3], Ok(Applied::Dispute(Amount::from_units(5_0000))));
+    assert!("withdrawals-only".parse::<DisputePolicy>().is_err());
+    Ok(())
+}
+
+/// check that replayed tx ids are rejected, and that disputes can't reference another client's tx
+#[test]
+pub fn test_duplicate_tx_ids() -> Result<()> {
+    let data = r#"
+       type, client ,tx , amount
+       deposit, 1, 1, 5
+       deposit, 1, 1, 5
+       deposit, 2, 1, 5
+       withdrawal, 2, 2, 10
+       deposit, 2, 2, 10
+       dispute, 2, 1,"#;
+    let rows = |scope| -> Result<Vec<_>> {
+        let mut accounts = Accounts::new().with_tx_id_scope(scope);
+        let mut rdr = ReaderBuilder::new()
+            .trim(Trim::All)
+            .flexible(true)
+            .from_reader(data.as_bytes());
+        let results = rdr
+            .deserialize()
+            .map(|result| Ok(accounts.process_transaction(&result?)))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(results)
+    };
+    let global = rows(TxIdScope::Global)?;
+    assert_eq!(
+        global[1],
+        Err(TxRejection::DuplicateTx {
+            client_id: 1,
+            tx_id: 1
+        })
+    );
+    assert_eq!(
+        global[2],
+        Err(TxRejection::DuplicateTx {
+            client_id: 2,
+            tx_id: 1
+        })
+    );
+    // a rejected withdrawal still uses up its tx id, so a replay of it can never apply
+    assert_eq!(
+        global[4],
+        Err(TxRejection::DuplicateTx {
+            client_id: 2,
+            tx_id: 2
+        })
+    );
+    assert_eq!(
+        global[5],
+        Err(TxRejection::ForeignTx {
+            client_id: 2,
+            tx_id: 1,
+            owner_id: 1
+        })
+    );
+
+    let per_client = rows(TxIdScope::PerClient)?;
+    assert!(per_client[1].is_err());
+    assert_eq!(
+        per_client[2],
+        Ok(Applied::Deposit(Amount::from_units(5_0000)))
+    );
+    assert_eq!(
+        per_client[5],
+        Ok(Applied::Dispute(Amount::from_units(5_0000)))
+    );
+    Ok(())
+}
+
+/// pseudo-random csv of every transaction type, including reused and foreign tx ids
+fn random_data(rows: usize, seed: u64) -> String {
+    let mut state = seed;
+    let mut next = |n: u64| {
+        state = state
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        (state >> 33) % n
+    };
+    let mut data = String::from("type,client,tx,amount,reason\n");
+    for _ in 0..rows {
+        let client = next(20) + 1;
+        let tx = next(rows as u64) + 1;
+        // half of the disputes, resolves and chargebacks are for part of the amount
+        let part = match next(2) {
+            0 => String::new(),
+            _ => format!("{}.{:04}", next(20) + 1, next(10000)),
+        };
+        // admin operations also go to a few clients that never open an account
+        let admin_client = next(24) + 1;
+        let line = match next(20) {
+            0..=7 => format!(
+                "deposit,{},{},{}.{:04},\n",
+                client,
+                tx,
+                next(100),
+                next(10000)
+            ),
+            8..=10 => format!(
+                "withdrawal,{},{},{}.{:04},\n",
+                client,
+                tx,
+                next(50),
+                next(10000)
+            ),
+            11..=13 => format!("dispute,{},{},{},\n", client, tx, part),
+            14 => format!("resolve,{},{},{},\n", client, tx, part),
+            15 => format!("chargeback,{},{},{},\n", client, tx, part),
+            16 => format!(
+                "adjust,{},{},{}{}.{:04},{}\n",
+                admin_client,
+                tx,
+                ["", "-"][next(2) as usize],
+                next(10) + 1,
+                next(10000),
+                next(5) + 1
+            ),
+            17 => format!("lock,{},{},,\n", admin_client, tx),
+            18 => format!("unlock,{},{},,\n", admin_client, tx),
+            _ => format!("close,{},{},,\n", admin_client, tx),
+        };
+        data.push_str(&line);
+    }
+    data
+}
+
+/// check that worker threads give the same accounts, rejections and summary as a single thread,
+/// and that a worker store that can't be created leaves the ledger as it was
+#[test]
+pub fn test_parallel() -> Result<()> {
+    let data = random_data(6000, 7);
+    // start the parallel run from existing state, to cover splitting it across workers
+    let mid = data[..data.len() / 2].rfind('\n').unwrap() + 1;
+    let (head, tail) = data.split_at(mid);
+    let tail = format!("type,client,tx,amount,reason\n{}", tail);
+    for scope in [TxIdScope::Global, TxIdScope::PerClient] {
+        let run = |workers: usize| -> Result<(Vec<u8>, Vec<String>, Summary)> {
+            let mut ledger = Ledger::new().with_tx_id_scope(scope);
+            ledger.process_csv(head.as_bytes(), |_, _| Ok(()))?;
+            let mut rejections = Vec::new();
+            let on_rejected = |row: &Row, reason: &dyn Display| {
+                rejections.push(format!("{} {}", row.line(), reason));
+                Ok(())
+            };
+            if workers > 1 {
+                ledger.process_csv_parallel(
+                    tail.as_bytes(),
+                    workers,
+                    |_| Ok(Default::default()),
+                    on_rejected,
+                )?;
+            } else {
+                ledger.process_csv(tail.as_bytes(), on_rejected)?;
+            }
+            let mut snapshot = Vec::new();
+            ledger.save_snapshot(&mut snapshot)?;
+            rejections.sort();
+            Ok((snapshot, rejections, ledger.summary()))
+        };
+        let (sequential, sequential_rejections, sequential_summary) = run(1)?;
+        assert!(!sequential_rejections.is_empty());
+        for workers in [2, 4] {
+            let (parallel, parallel_rejections, parallel_summary) = run(workers)?;
+            assert_eq!(parallel, sequential);
+            assert_eq!(parallel_rejections, sequential_rejections);
+            assert_eq!(parallel_summary, sequential_summary);
+        }
+    }
+
+    // a worker store that can't be created leaves the ledger as it was
+    let mut ledger = Ledger::new();
+    ledger.process_csv(head.as_bytes(), |_, _| Ok(()))?;
+    let before = ledger.clone();
+    let result = ledger.process_csv_parallel(
+        tail.as_bytes(),
+        3,
+        |worker| match worker {
+            2 => Err(anyhow::anyhow!("no store for worker {}", worker)),
+            _ => Ok(Default::default()),
+        },
+        |_, _| Ok(()),
+    );
+    assert!(result.is_err());
+    assert_eq!(ledger, before);
+    Ok(())
+}
+
+/// check that several inputs can be processed in order into the same ledger
+#[test]
+pub fn test_multiple_inputs() -> Result<()> {
+    let monday = "type,client,tx,amount\ndeposit,1,1,2.0\ndeposit,1,2,1.0\n";
+    let tuesday = "type,client,tx,amount\ndispute,1,1,\ndeposit,1,2,1.0\n";
+    let mut ledger = Ledger::new();
+    assert_eq!(ledger.process_csv(monday.as_bytes(), |_, _| Ok(()))?, 2);
+    let mut rejected = 0;
+    let rows = ledger.process_csv(tuesday.as_bytes(), |_, _| {
+        rejected += 1;
+        Ok(())
+    })?;
+    assert_eq!((rows, rejected), (2, 1));
+    let account = ledger.account(1).unwrap();
+    assert_eq!(account.held_bal, Amount::from_units(2_0000));
+    assert_eq!(account.total_bal, Amount::from_units(3_0000));
+    Ok(())
+}
+
+/// check that malformed rows name their line and failing field, are skipped in lenient mode,
+/// and are copied as written after the header of their input in quarantine mode
+#[test]
+pub fn test_parse_mode() -> Result<()> {
+    let data = "type,client,tx,amount\n\
+                deposit,1,1,2.0\n\
+                deposit,1,x,1.0\n\
+                deposit,1,3,1.00001\n\
+                refund,1,4,1.0\n\
+                withdrawal,1,5\n\
+                deposit,1,6,1.0\n";
+    let mut rejected = vec![];
+    let mut ledger = Ledger::new().with_parse_mode(ParseMode::Lenient);
+    let rows = ledger.process_csv(data.as_bytes(), |row, reason| {
+        rejected.push((row.line(), reason.to_string()));
+        Ok(())
+    })?;
+    assert_eq!(rows, 6);
+    assert_eq!(
+        rejected,
+        vec![
+            (3, "invalid tx: invalid digit found in string".to_string()),
+            (
+                4,
+                "invalid amount: \"1.00001\": amount has more than 4 decimal places".to_string()
+            ),
+            (
+                5,
+                "invalid type: unknown variant `refund`, expected one of `deposit`, \
+                 `withdrawal`, `dispute`, `resolve`, `chargeback`, `adjust`, `lock`, `unlock`, \
+                 `close`"
+                    .to_string()
+            ),
+            (6, "amount does not match transaction type".to_string()),
+        ]
+    );
+    assert_eq!(
+        ledger.account(1).map(|account| account.total_bal),
+        Some(Amount::from_units(3_0000))
+    );
+
+    let err = Ledger::new()
+        .process_csv(data.as_bytes(), |_, _| Ok(()))
+        .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "line 3: invalid tx: invalid digit found in string"
+    );
+
+    let path = std::env::temp_dir().join(format!("toy_txs_quarantine_{}.csv", std::process::id()));
+    let data = data.replace(',', ";").replace("3;1.00001", "3; 1.00001");
+    let reader = TransactionReader::with_options(
+        data.as_bytes(),
+        &ReaderOptions {
+            delimiter: Some(b';'),
+            ..ReaderOptions::default()
+        },
+    )?
+    .with_quarantine(Quarantine::new(std::fs::File::create(&path)?));
+    let mut ledger = Ledger::new().with_parse_mode(ParseMode::Quarantine);
+    assert_eq!(ledger.process_reader(reader, |_, _| Ok(()))?, 6);
+    let quarantined = std::fs::read_to_string(&path)?;
+    std::fs::remove_file(&path)?;
+    assert_eq!(
+        quarantined,
+        "type;client;tx;amount\n\
+         deposit;1;x;1.0\n\
+         deposit;1;3; 1.00001\n\
+         refund;1;4;1.0\n\
+         withdrawal;1;5\n"
+    );
+    Ok(())
+}
+
+/// check that JSON Lines input gives the same accounts as csv, with the same amount rules
+#[test]
+pub fn test_json_lines() -> Result<()> {
+    let jsonl = r#"{"type":"deposit","client":1,"tx":1,"amount":1.0}
+{"type":"deposit","client":2,"tx":2,"amount":"2.0"}
+{"type":"deposit","client":1,"tx":3,"amount":2}
+
+{"type":"withdrawal","client":1,"tx":4,"amount":1.5}
+{"type":"withdrawal","client":2,"tx":5,"amount":"3.0"}
+{"type":"dispute","client":1,"tx":1}
+{"type":"resolve","client":1,"tx":1,"amount":null}
+{"type":"dispute","client":2,"tx":2}
+{"type":"chargeback","client":2,"tx":2}
+{"type":"deposit","client":3,"tx":6,"amount":1.00001}
+"#;
+    let from_csv = data_1_ledger()?;
+    let mut from_json = Ledger::new().with_parse_mode(ParseMode::Lenient);
+    let mut rejected = vec![];
+    let rows = from_json.process_reader(
+        TransactionReader::json_lines(jsonl.as_bytes()),
+        |row, reason| {
+            rejected.push((row.line(), reason.to_string()));
+            Ok(())
+        },
+    )?;
+    assert_eq!(rows, 10);
+    assert_eq!(
+        rejected,
+        vec![
+            (
+                6,
+                "tx 5 would overdraw the available balance of client 2".to_string()
+            ),
+            (
+                11,
+                "invalid amount: \"1.00001\": amount has more than 4 decimal places".to_string()
+            ),
+        ]
+    );
+    assert_eq!(from_json.accounts().state, from_csv.accounts().state);
+
+    let mut out = vec![];
+    from_json.write_sorted(&mut out, SortKey::ClientId, OutputFormat::JsonLines)?;
+    assert_eq!(
+        String::from_utf8(out)?,
+        "{\"client\":1,\"available\":\"1.5000\",\"held\":\"0.0000\",\"total\":\"1.5000\",\"locked\":false}\n\
+         {\"client\":2,\"available\":\"0.0000\",\"held\":\"0.0000\",\"total\":\"0.0000\",\"locked\":true}\n"
+    );
+    Ok(())
+}
+
+/// check that the Arrow IPC export holds exact decimal amounts, and fails on amounts
+/// beyond its precision
+#[cfg(feature = "arrow")]
+#[test]
+pub fn test_arrow_export() -> Result<()> {
+    use arrow_array::cast::AsArray;
+    use arrow_array::types::{Decimal128Type, UInt16Type, UInt32Type};
+    use arrow_ipc::reader::FileReader;
+    use std::io::Cursor;
+
+    let ledger = data_1_ledger()?;
+
+    let mut accounts = vec![];
+    ledger.write_arrow_ipc(&mut accounts)?;
+    let batches =
+        FileReader::try_new(Cursor::new(accounts), None)?.collect::<Result<Vec<_>, _>>()?;
+    assert_eq!(batches.len(), 1);
+    let batch = &batches[0];
+    assert_eq!(batch.schema(), crate::parser::columnar::accounts_schema());
+    let clients = batch.column(0).as_primitive::<UInt16Type>();
+    let totals = batch.column(3).as_primitive::<Decimal128Type>();
+    assert_eq!(clients.values().to_vec(), vec![1, 2]);
+    assert_eq!(totals.values().to_vec(), vec![1_5000, 0]);
+    assert_eq!(totals.value_as_string(0), "1.5000");
+    assert!(batch.column(4).as_boolean().value(1));
+
+    let mut history = vec![];
+    ledger.write_history_arrow_ipc(&mut history)?;
+    let batches =
+        FileReader::try_new(Cursor::new(history), None)?.collect::<Result<Vec<_>, _>>()?;
+    let batch = &batches[0];
+    assert_eq!(
+        batch
+            .column(2)
+            .as_primitive::<UInt32Type>()
+            .values()
+            .to_vec(),
+        vec![1, 3, 4, 2]
+    );
+    assert_eq!(batch.column(0).as_string::<i32>().value(2), "withdrawal");
+    assert_eq!(
+        batch.column(3).as_primitive::<Decimal128Type>().value(2),
+        1_5000
+    );
+
+    let mut ledger = Ledger::new();
+    ledger.process_csv(
+        "type,client,tx,amount\ndeposit,1,1,10000000000000000000000000000000000".as_bytes(),
+        |_, reason| panic!("{}", reason),
+    )?;
+    let err = ledger.write_arrow_ipc(vec![]).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "amount 10000000000000000000000000000000000.0000 has more than 38 digits"
+    );
+    assert!(ledger.write_history_arrow_ipc(vec![]).is_err());
+    Ok(())
+}
+
+/// check that columns are found by renamed or differently cased headers, or by position
+#[test]
+pub fn test_column_mapping() -> Result<()> {
+    let expected = data_1_ledger()?;
+
+    let renamed = "Amount,ID,Customer_Id,note,TYPE
+1.0,1,1,x,deposit
+2.0,2,2,,deposit
+2.0,3,1,,deposit
+1.5,4,1,,withdrawal
+3.0,5,2,,withdrawal
+,1,1,,dispute
+,1,1,,resolve
+,2,2,,dispute
+,2,2,,chargeback";
+    let mut columns = ColumnMapping::default();
+    columns.rename("tx", "id")?;
+    columns.rename("Client", "customer_id")?;
+    let mut ledger = Ledger::new();
+    ledger.process_reader(
+        TransactionReader::csv(renamed.as_bytes(), &columns)?,
+        |_, _| Ok(()),
+    )?;
+    assert_eq!(ledger.accounts().state, expected.accounts().state);
+
+    let headerless = "1,1,deposit,1.0
+2,2,deposit,2.0
+1,3,deposit,2.0
+1,4,withdrawal,1.5
+2,5,withdrawal,3.0
+1,1,dispute
+1,1,resolve
+2,2,dispute
+2,2,chargeback";
+    let columns = ColumnMapping::from_json(
+        r#"{"positions": ["client", "tx", "type", "amount"]}"#.as_bytes(),
+    )?;
+    let mut ledger = Ledger::new();
+    let rows = ledger.process_reader(
+        TransactionReader::csv(headerless.as_bytes(), &columns)?,
+        |_, _| Ok(()),
+    )?;
+    assert_eq!(rows, 9);
+    assert_eq!(ledger.accounts().state, expected.accounts().state);
+
+    // the tx column is still expected under its default name, which is missing
+    let mut columns = ColumnMapping::default();
+    columns.rename("client", "customer_id")?;
+    let err = TransactionReader::csv("type,customer_id,id\n".as_bytes(), &columns).err();
+    assert_eq!(err.unwrap().to_string(), "no \"tx\" column in the header");
+    assert!(columns.rename("account", "x").is_err());
+    Ok(())
+}
+
+/// check delimiters, quoting, comments, byte order marks and encodings of csv input
+#[test]
+pub fn test_csv_dialect() -> Result<()> {
+    let expected = data_1_ledger()?;
+    let read = |input: &[u8], options: &ReaderOptions| -> Result<_> {
+        let mut ledger = Ledger::new();
+        let rows = ledger
+            .process_reader(TransactionReader::with_options(input, options)?, |_, _| {
+                Ok(())
+            })?;
+        Ok((rows, ledger.accounts().state.clone()))
+    };
+
+    let semicolons = "\u{feff}# exported 2021-01-01; from the bank
+type;client;tx;amount;note
+deposit;1;1;1.0;'a;b'
+deposit;2;2;2.0;
+# the next two rows are withdrawals
+deposit;1;3;2.0;
+withdrawal;1;4;1.5;
+withdrawal;2;5;3.0;
+dispute;1;1;;
+resolve;1;1;;
+dispute;2;2;;'\nnew line'
+chargeback;2;2;;";
+    let options = ReaderOptions {
+        quote: b'\'',
+        comment: Some(b'#'),
+        ..ReaderOptions::default()
+    };
+    assert_eq!(
+        read(semicolons.as_bytes(), &options)?,
+        (9, expected.accounts().state.clone())
+    );
+
+    // the amount column is named "montant réel", so it is only found if decoded correctly
+    let tabs = DATA_1
+        .trim()
+        .replace(" amount", "montant r\u{e9}el")
+        .replace(',', "\t");
+    let (latin1, _, _) = encoding_rs::WINDOWS_1252.encode(&tabs);
+    let mut options = ReaderOptions {
+        encoding: Some(encoding_rs::WINDOWS_1252),
+        ..ReaderOptions::default()
+    };
+    options.columns.rename("amount", "montant r\u{e9}el")?;
+    assert_eq!(
+        read(&latin1, &options)?,
+        (9, expected.accounts().state.clone())
+    );
+    options.encoding = None;
+    assert!(read(&latin1, &options).is_err());
+
+    // quarantined rows are written back in the encoding of the input
+    let path = std::env::temp_dir().join(format!("toy_txs_dialect_{}.csv", std::process::id()));
+    let bad = "type\tclient\ttx\tmontant r\u{e9}el\ndeposit\t1\t1\t1\ndeposit\t1\t2\tun \u{e9}cu\n";
+    for encoding in [encoding_rs::WINDOWS_1252, encoding_rs::UTF_16LE] {
+        let input: Vec<u8> = if encoding == encoding_rs::UTF_16LE {
+            bad.encode_utf16().flat_map(u16::to_le_bytes).collect()
+        } else {
+            encoding.encode(bad).0.into_owned()
+        };
+        options.encoding = Some(encoding);
+        let reader = TransactionReader::with_options(&input[..], &options)?
+            .with_quarantine(Quarantine::new(std::fs::File::create(&path)?));
+        let mut ledger = Ledger::new().with_parse_mode(ParseMode::Quarantine);
+        assert_eq!(ledger.process_reader(reader, |_, _| Ok(()))?, 2);
+        let quarantined = std::fs::read(&path)?;
+        let (decoded, _, malformed) = encoding.decode(&quarantined);
+        assert!(!malformed);
+        assert_eq!(
+            decoded,
+            "type\tclient\ttx\tmontant r\u{e9}el\ndeposit\t1\t2\tun \u{e9}cu\n"
+        );
+    }
+    std::fs::remove_file(&path)?;
+
+    assert_eq!(detect_delimiter(b"type|client|tx|amount\n", b'"'), b'|');
+    assert_eq!(detect_delimiter(b"\"a;b\",c\nx;y;z", b'"'), b',');
+    assert_eq!(detect_delimiter(b"type", b'"'), b',');
+    Ok(())
+}
+
+/// check that compressed input is detected and decompressed, and that output can be compressed
+#[cfg(all(feature = "gzip", feature = "zstd"))]
+#[test]
+pub fn test_compression() -> Result<()> {
+    use crate::parser::compression::{decompress, Compression};
+    use crate::parser::reader::InputFormat;
+    use std::io::Write;
+    use std::path::Path;
+
+    let expected = data_1_ledger()?;
+    for compression in [Compression::None, Compression::Gzip, Compression::Zstd] {
+        let mut compressed = vec![];
+        let mut encoder = compression.encoder(&mut compressed)?;
+        encoder.write_all(DATA_1.trim().as_bytes())?;
+        encoder.finish()?;
+        assert_eq!(Compression::detect(&compressed), compression);
+
+        let mut ledger = Ledger::new();
+        let rows = ledger.process_csv(decompress(compressed.as_slice())?, |_, _| Ok(()))?;
+        assert_eq!(rows, 9);
+        assert_eq!(ledger.accounts().state, expected.accounts().state);
+    }
+
+    assert_eq!(
+        Compression::from_path(Path::new("txs.csv.zst")),
+        Some(Compression::Zstd)
+    );
+    assert_eq!(
+        InputFormat::from_path(Path::new("txs.jsonl.gz")),
+        Some(InputFormat::JsonLines)
+    );
+    assert_eq!(
+        OutputFormat::from_path(Path::new("accounts.csv.gz")),
+        Some(OutputFormat::Csv)
+    );
+    assert_eq!(InputFormat::from_path(Path::new("txs.gz")), None);
+    Ok(())
+}
+
+/// check the counts of the run summary
+#[test]
+pub fn test_summary() -> Result<()> {
+    let data = format!("{}\n       deposit, 3, 9, abc", DATA_1.trim());
+    let mut ledger = Ledger::new().with_parse_mode(ParseMode::Lenient);
+    ledger.process_csv(data.as_bytes(), |_, _| Ok(()))?;
+    let summary = ledger.summary();
+    let stats = &summary.stats;
+    assert_eq!(summary.rows_read, 10);
+    assert_eq!((stats.transactions, stats.malformed), (9, 1));
+    assert_eq!((stats.applied, stats.rejected), (8, 1));
+    assert_eq!(
+        stats.rejected_by_reason.iter().collect::<Vec<_>>(),
+        vec![(&"insufficient_funds", &1)]
+    );
+    assert_eq!((stats.deposits, stats.withdrawals), (3, 1));
+    assert_eq!(
+        (
+            stats.disputes_opened,
+            stats.disputes_resolved,
+            stats.chargebacks
+        ),
+        (2, 1, 1)
+    );
+    assert_eq!((stats.accounts_created, stats.accounts_locked), (2, 1));
+    assert_eq!(stats.overflows, 0);
+    // the chargeback of a deposit takes money out, like a withdrawal
+    assert_eq!(stats.money_in, Amount::from_units(5_0000));
+    assert_eq!(stats.money_out, Amount::from_units(3_5000));
+    assert_eq!(stats.charged_back, Amount::from_units(2_0000));
+    assert_eq!(summary.money_held, Amount::ZERO);
+
+    let json = serde_json::to_string(&summary)?;
+    assert!(json.starts_with(r#"{"rows_read":10,"transactions":9,"malformed":1,"#));
+    assert!(summary
+        .to_string()
+        .ends_with("money: 5.0000 in, 3.5000 out, 0.0000 held"));
+
+    // and the chargeback of a withdrawal brings it back in
+    let mut ledger = Ledger::new();
+    ledger.process_csv(DATA_5.trim().as_bytes(), |_, _| Ok(()))?;
+    let stats = ledger.summary().stats;
+    assert_eq!(
+        (stats.money_in, stats.money_out),
+        (Amount::from_units(8_0000), Amount::from_units(3_0000))
+    );
+
+    // stats are not part of the state
+    let mut snapshot = vec![];
+    ledger.save_snapshot(&mut snapshot)?;
+    let restored = Ledger::new().load_snapshot(snapshot.as_slice())?;
+    assert_eq!(restored.accounts(), ledger.accounts());
+    assert_eq!(restored.summary().stats, Default::default());
+    Ok(())
+}
+
+/// check that valid accounts verify, and that broken invariants are each reported
+#[test]
+pub fn test_verify() -> Result<()> {
+    // DATA_5 charges back a withdrawal
+    for data in [random_data(3000, 11).as_str(), DATA_5] {
+        let mut ledger = Ledger::new().with_verify_each(true);
+        ledger.process_csv(data.as_bytes(), |_, _| Ok(()))?;
+        assert_eq!(ledger.verify()?, vec![]);
+    }
+
+    // disputing a tx twice holds its funds once
+    let tx = |transaction_type, tx_id, amount: Option<i128>| Transaction {
+        transaction_type,
+        client_id: 1,
+        tx_id,
+        amount: amount.map(Amount::from_units),
+        reason: None,
+    };
+    let mut accounts = Accounts::new().with_verify_each(true);
+    let deposit = tx(TransactionType::Deposit, 1, Some(5_0000));
+    let dispute = tx(TransactionType::Dispute, 1, None);
+    assert!(accounts.process_transaction(&deposit).is_ok());
+    assert!(accounts.process_transaction(&dispute).is_ok());
+    let rejection = accounts.process_transaction(&dispute).unwrap_err();
+    assert!(!rejection.is_fatal());
+    assert_eq!(accounts.state[&1].held_bal, Amount::from_units(5_0000));
+
+    // a broken account is reported as a fatal error rather than a panic
+    assert_eq!(accounts.take_fatal_error(), None);
+    accounts.state.get_mut(&1).unwrap().total_bal = Amount::ZERO;
+    let deposit = tx(TransactionType::Deposit, 2, Some(1));
+    assert!(accounts.process_transaction(&deposit).is_ok());
+    assert!(matches!(
+        accounts.take_fatal_error(),
+        Some(FatalError::Invariant { tx_id: 2, .. })
+    ));
+
+    let mut accounts = Accounts::new();
+    for tx in &DATA_1_EXPECTED_TXS {
+        let _ = accounts.process_transaction(tx);
+    }
+    assert_eq!(accounts.verify()?, vec![]);
+
+    // charged back amounts are kept in snapshots, so a restored snapshot still verifies
+    let mut snapshot = vec![];
+    accounts.save_snapshot(&mut snapshot)?;
+    let restored = Accounts::new().load_snapshot(snapshot.as_slice())?;
+    assert_eq!(restored, accounts);
+    assert_eq!(restored.verify()?, vec![]);
+
+    let account = accounts.state.get_mut(&1).unwrap();
+    account.held_bal = Amount::from_units(1);
+    account.disputes.insert(9, Amount::from_units(1));
+    assert_eq!(
+        accounts.verify()?,
+        vec![
+            Violation::TotalMismatch {
+                client_id: 1,
+                available: Amount::from_units(1_5000),
+                held: Amount::from_units(1),
+                total: Amount::from_units(1_5000),
+            },
+            Violation::UnknownDispute {
+                client_id: 1,
+                tx_id: 9
+            },
+            Violation::HeldMismatch {
+                client_id: 1,
+                held: Amount::from_units(1),
+                disputed: Amount::ZERO,
+            },
+        ]
+    );
+
+    let account = accounts.state.get_mut(&2).unwrap();
+    account.total_bal = Amount::from_units(1_0000);
+    account.avail_bal = Amount::from_units(1_0000);
+    let violations = accounts.verify()?;
+    assert_eq!(
+        violations[3..],
+        [
+            Violation::ClientConservation {
+                client_id: 2,
+                applied: Amount::from_units(2_0000),
+                charged_back: Amount::from_units(2_0000),
+                total: Amount::from_units(1_0000),
+            },
+            Violation::Conservation {
+                applied: Amount::from_units(3_5000),
+                charged_back: Amount::from_units(2_0000),
+                totals: Amount::from_units(2_5000),
+            },
+        ]
+    );
+    Ok(())
+}
+
+/// check that every applied transaction is journaled with its balances before and after, that a
+/// failing journal stops processing, and that workers journal the same events for each client
+#[test]
+pub fn test_journal() -> Result<()> {
+    let collect = || {
+        let events = Arc::new(Mutex::new(Vec::<Event>::new()));
+        let sink = events.clone();
+        let journal = Journal::new(move |batch: &[Event]| -> Result<()> {
+            sink.lock().unwrap().extend_from_slice(batch);
+            Ok(())
+        });
+        (events, journal)
+    };
+
+    let (events, journal) = collect();
+    let mut ledger = Ledger::new().with_journal(journal);
+    ledger.process_csv(DATA_5.as_bytes(), |_, _| Ok(()))?;
+    let events = events.lock().unwrap();
+    let kinds: Vec<(EventKind, u32)> = events.iter().map(|e| (e.event, e.tx)).collect();
+    assert_eq!(
+        kinds,
+        vec![
+            (EventKind::Opened, 1),
+            (EventKind::Credited, 1),
+            (EventKind::Debited, 2),
+            (EventKind::Held, 2),
+            (EventKind::ChargedBack, 2),
+            (EventKind::Locked, 2),
+        ]
+    );
+    // every event starts where the previous one ended
+    for pair in events.windows(2) {
+        assert_eq!(pair[0].after, pair[1].before);
+    }
+    let charged_back = &events[4];
+    assert_eq!(charged_back.amount, Some(Amount::from_units(3_0000)));
+    assert_eq!(charged_back.after.total, Amount::from_units(5_0000));
+    assert!(!charged_back.after.locked && events[5].after.locked);
+
+    // a failing journal stops processing, but the transaction it failed on still stands
+    let failing = Journal::new(|_: &[Event]| -> Result<()> { anyhow::bail!("disk full") });
+    let mut accounts = Accounts::new().with_journal(failing.clone());
+    assert_eq!(
+        accounts.process_transaction(&DATA_1_EXPECTED_TXS[0]),
+        Ok(Applied::Deposit(Amount::from_units(1_0000)))
+    );
+    assert_eq!(
+        accounts.take_fatal_error(),
+        Some(FatalError::Journal {
+            client_id: 1,
+            tx_id: 1,
+            reason: "disk full".to_string()
+        })
+    );
+    assert_eq!(accounts.state[&1].total_bal, Amount::from_units(1_0000));
+    let mut ledger = Ledger::new().with_journal(failing);
+    let err = ledger
+        .process_csv(DATA_1.trim().as_bytes(), |_, _| Ok(()))
+        .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "journal failed on tx 1 of client 1: disk full"
+    );
+    assert_eq!(ledger.summary().stats.applied, 1);
+
+    // a rejected withdrawal still claims its tx id
+    let (events, journal) = collect();
+    let mut ledger = Ledger::new().with_journal(journal);
+    ledger.process_csv(DATA_1.as_bytes(), |_, _| Ok(()))?;
+    let events = events.lock().unwrap();
+    let claimed: Vec<u32> = events
+        .iter()
+        .filter(|e| e.event == EventKind::Claimed)
+        .map(|e| e.tx)
+        .collect();
+    assert_eq!(claimed, vec![5]);
+
+    // workers journal the same events for each client
+    let data = random_data(3000, 13);
+    let mut runs = vec![];
+    for workers in [1, 3] {
+        let (events, journal) = collect();
+        let mut ledger = Ledger::new().with_journal(journal);
+        if workers > 1 {
+            ledger.process_csv_parallel(
+                data.as_bytes(),
+                workers,
+                |_| Ok(Default::default()),
+                |_, _| Ok(()),
+            )?;
+        } else {
+            ledger.process_csv(data.as_bytes(), |_, _| Ok(()))?;
+        }
+        let mut events = events.lock().unwrap().clone();
+        // stable, so each client's events keep their order
+        events.sort_by_key(|e| e.client);
+        runs.push(events);
+    }
+    assert_eq!(runs[0], runs[1]);
+    Ok(())
+}
+
+/// check that replaying the journal rebuilds the same accounts, that differing balances are
+/// reported as mismatches, and that an event missing from the journal is detected
+#[test]
+pub fn test_rebuild() -> Result<()> {
+    for scope in [TxIdScope::Global, TxIdScope::PerClient] {
+        let journal = Arc::new(Mutex::new(Vec::new()));
+        let sink = journal.clone();
+        let mut ledger = Ledger::new()
+            .with_tx_id_scope(scope)
+            .with_journal(Journal::new(move |events: &[Event]| -> Result<()> {
+                let mut w = sink.lock().unwrap();
+                for event in events {
+                    serde_json::to_writer(&mut *w, event)?;
+                    w.push(b'\n');
+                }
+                Ok(())
+            }));
+        ledger.process_csv(random_data(3000, 17).as_bytes(), |_, _| Ok(()))?;
+        let journal = journal.lock().unwrap().clone();
+
+        let rebuilt = Accounts::new()
+            .with_tx_id_scope(scope)
+            .replay_journal(journal.as_slice())?;
+        assert_eq!(rebuilt.compare(ledger.accounts())?, vec![]);
+        assert_eq!(&rebuilt, ledger.accounts());
+
+        let mut csv = Vec::new();
+        ledger.write_sorted(&mut csv, SortKey::ClientId, OutputFormat::Csv)?;
+        let mut expected = read_accounts_csv(csv.as_slice())?;
+        assert_eq!(rebuilt.compare_balances(&expected), vec![]);
+        let (&client_id, balances) = expected.iter_mut().next().unwrap();
+        let journaled = *balances;
+        balances.locked = !balances.locked;
+        expected.insert(u16::MAX, Balances::default());
+        assert_eq!(
+            rebuilt.compare_balances(&expected),
+            vec![
+                Mismatch::Balances {
+                    client_id,
+                    journal: journaled,
+                    expected: expected[&client_id],
+                },
+                Mismatch::MissingAccount {
+                    client_id: u16::MAX
+                },
+            ]
+        );
+
+        // an event missing from the journal breaks the chain of balances
+        let journal = String::from_utf8(journal)?;
+        let credit = journal.find(r#"{"event":"credited""#).unwrap();
+        let end = credit + journal[credit..].find('\n').unwrap() + 1;
+        let gapped = format!("{}{}", &journal[..credit], &journal[end..]);
+        let err = Accounts::new()
+            .with_tx_id_scope(scope)
+            .replay_journal(gapped.as_bytes())
+            .unwrap_err();
+        assert!(format!("{:#}", err).contains("before it, but the account has"));
+    }
+    Ok(())
+}
+
+/// check that --as-of is parsed, and that processing stops at the given tx or row with the
+/// same accounts as the input cut right after it
+#[test]
+pub fn test_as_of() -> Result<()> {
+    assert_eq!("tx:10000".parse::<AsOf>()?, AsOf::Tx(10000));
+    assert_eq!("row:7".parse::<AsOf>()?, AsOf::Row(7));
+    assert!("10000".parse::<AsOf>().is_err());
+    assert!("tx:-1".parse::<AsOf>().is_err());
+
+    let prefix = |data: &str, rows: usize| -> Result<Ledger> {
+        let lines: Vec<&str> = data.trim().lines().take(rows + 1).collect();
+        let mut ledger = Ledger::new();
+        ledger.process_csv(lines.join("\n").as_bytes(), |_, _| Ok(()))?;
+        Ok(ledger)
+    };
+    let until = |inputs: &[&str], as_of: AsOf| -> Result<(Ledger, bool)> {
+        let mut ledger = Ledger::new();
+        for input in inputs {
+            let reader = TransactionReader::new(input.as_bytes())?;
+            let (_, reached) = ledger.process_reader_until(reader, as_of, |_, _| Ok(()))?;
+            if reached {
+                return Ok((ledger, true));
+            }
+        }
+        Ok((ledger, false))
+    };
+
+    // the withdrawal that claimed tx 4 is the fourth row
+    let (ledger, reached) = until(&[DATA_1], AsOf::Tx(4))?;
+    assert!(reached);
+    assert_eq!(ledger.accounts().state, prefix(DATA_1, 4)?.accounts().state);
+    assert_eq!(
+        ledger.account(1).map(|a| a.avail_bal),
+        Some(Amount::from_units(1_5000))
+    );
+    assert!(!until(&[DATA_1], AsOf::Tx(42))?.1);
+
+    // rows are counted across inputs
+    let data = random_data(400, 19);
+    let mid = data.trim().lines().count() / 2;
+    let lines: Vec<&str> = data.trim().lines().collect();
+    let head = lines[..mid].join("\n");
+    let tail = format!("{}\n{}", lines[0], lines[mid..].join("\n"));
+    for rows in [0, 1, mid as u64 - 1, 300] {
+        let (ledger, reached) = until(&[&head, &tail], AsOf::Row(rows))?;
+        assert!(reached);
+        assert_eq!(
+            ledger.accounts().state,
+            prefix(&data, rows as usize)?.accounts().state
+        );
+    }
+    assert!(!until(&[&head, &tail], AsOf::Row(lines.len() as u64))?.1);
+    Ok(())
+}
+
+const DATA_ADMIN: &str = r#"
+       type, client, tx, amount, reason
+       deposit, 1, 1, 5
+       dispute, 1, 1,
+       chargeback, 1, 1,
+       deposit, 1, 2, 1
+       unlock, 1, 3, , 7
+       unlock, 1, 4,
+       deposit, 1, 5, 2
+       adjust, 1, 6, -2.5, 42
+       lock, 1, 7,
+       lock, 1, 8,
+       withdrawal, 1, 9, 1
+       adjust, 1, 10, 0.5, 42
+       close, 1, 11,
+       adjust, 1, 12, -0.0000, 1
+       unlock, 1, 13,
+       close, 1, 14,
+       deposit, 2, 15, 1, 3"#;
+
+/// check that adjustments, locks, unlocks and closes are applied or rejected as expected, that
+/// they can be kept to the admin input, and that they are rejected on clients without an account
+#[test]
+pub fn test_admin() -> Result<()> {
+    let rows: Vec<_> = TransactionReader::new(DATA_ADMIN.as_bytes())?.collect::<Result<_>>()?;
+    let mut accounts = Accounts::new();
+    let results: Vec<_> = rows
+        .iter()
+        .filter_map(|row| row.transaction.as_ref().ok())
+        .map(|tx| accounts.process_transaction(tx))
+        .collect();
+    let rejected = |tx_id| (1, tx_id);
+    let as_pair = |rejection: &TxRejection| match *rejection {
+        TxRejection::AccountLocked { client_id, tx_id }
+        | TxRejection::NotLocked { client_id, tx_id }
+        | TxRejection::NotEmpty { client_id, tx_id }
+        | TxRejection::AccountClosed { client_id, tx_id } => (client_id, tx_id),
+        _ => panic!("unexpected {}", rejection),
+    };
+    assert_eq!(
+        results,
+        vec![
+            Ok(Applied::Deposit(Amount::from_units(5_0000))),
+            Ok(Applied::Dispute(Amount::from_units(5_0000))),
+            Ok(Applied::Chargeback(Amount::from_units(5_0000))),
+            Err(TxRejection::AccountLocked {
+                client_id: 1,
+                tx_id: 2
+            }),
+            Ok(Applied::Unlock),
+            Err(TxRejection::NotLocked {
+                client_id: 1,
+                tx_id: 4
+            }),
+            Ok(Applied::Deposit(Amount::from_units(2_0000))),
+            Ok(Applied::Adjustment(Amount::from_units(-2_5000))),
+            Ok(Applied::Lock),
+            Err(TxRejection::AccountLocked {
+                client_id: 1,
+                tx_id: 8
+            }),
+            Err(TxRejection::AccountLocked {
+                client_id: 1,
+                tx_id: 9
+            }),
+            Ok(Applied::Adjustment(Amount::from_units(5000))),
+            Ok(Applied::Close),
+            Err(TxRejection::AccountClosed {
+                client_id: 1,
+                tx_id: 13
+            }),
+            Err(TxRejection::AccountClosed {
+                client_id: 1,
+                tx_id: 14
+            }),
+        ]
+    );
+    assert_eq!(
+        results
+            .iter()
+            .filter_map(|result| result.as_ref().err())
+            .map(as_pair)
+            .collect::<Vec<_>>(),
+        [2, 4, 8, 9, 13, 14].map(rejected).to_vec()
+    );
+    // a zero adjustment, and a reason on a deposit, do not parse
+    let errors: Vec<String> = rows
+        .iter()
+        .filter_map(|row| row.transaction.as_ref().err())
+        .map(RowError::to_string)
+        .collect();
+    assert_eq!(
+        errors,
+        vec![
+            "amount does not match transaction type",
+            "reason does not match transaction type"
+        ]
+    );
+
+    let account = &accounts.state[&1];
+    assert!(account.closed && account.locked);
+    assert_eq!(account.total_bal, Amount::ZERO);
+    assert_eq!(accounts.stats.adjustments, 2);
+    assert_eq!(accounts.stats.adjusted, Amount::from_units(-2_0000));
+    assert_eq!(accounts.stats.accounts_closed, 1);
+    // adjustments count towards the conservation of money
+    assert_eq!(accounts.verify()?, vec![]);
+
+    let mut snapshot = Vec::new();
+    accounts.save_snapshot(&mut snapshot)?;
+    let restored = Accounts::new().load_snapshot(snapshot.as_slice())?;
+    assert_eq!(restored, accounts);
+    assert_eq!(restored.store.get(1, 6)?.and_then(|tx| tx.reason), Some(42));
+
+    // admin operations may be kept to the admin input
+    let lock = Transaction {
+        transaction_type: TransactionType::Lock,
+        client_id: 3,
+        tx_id: 1,
+        amount: None,
+        reason: None,
+    };
+    let deposit = Transaction {
+        transaction_type: TransactionType::Deposit,
+        amount: Some(Amount::from_units(1_0000)),
+        ..lock
+    };
+    let mut accounts = Accounts::new().with_admin_policy(AdminPolicy::AdminInputOnly);
+    assert!(accounts.process_transaction(&deposit).is_ok());
+    let lock = Transaction { tx_id: 2, ..lock };
+    assert_eq!(
+        accounts.process_transaction(&lock),
+        Err(TxRejection::AdminOnly {
+            client_id: 3,
+            tx_id: 2
+        })
+    );
+    assert_eq!(
+        accounts.process_admin_transaction(&Transaction { tx_id: 3, ..lock }),
+        Ok(Applied::Lock)
+    );
+
+    // but not on a client without an account, which is left without one
+    let close = Transaction {
+        transaction_type: TransactionType::Close,
+        client_id: 4,
+        ..lock
+    };
+    assert_eq!(
+        accounts.process_admin_transaction(&close),
+        Err(TxRejection::UnknownClient {
+            client_id: 4,
+            tx_id: 2
+        })
+    );
+    assert!(!accounts.state.contains_key(&4));
+    assert_eq!(accounts.stats.accounts_created, 1);
+
+    // unlocking an account does not make its charged back tx disputable again
+    let mut accounts = Accounts::new();
+    for tx in rows[..3]
+        .iter()
+        .filter_map(|row| row.transaction.as_ref().ok())
+    {
+        accounts.process_transaction(tx)?;
+    }
+    let unlock = rows[4].transaction.as_ref().unwrap();
+    assert_eq!(accounts.process_transaction(unlock), Ok(Applied::Unlock));
+    let dispute = rows[1].transaction.as_ref().unwrap();
+    assert_eq!(
+        accounts.process_transaction(dispute),
+        Err(TxRejection::NotDisputable {
+            client_id: 1,
+            tx_id: 1
+        })
+    );
+    assert_eq!(accounts.state[&1].total_bal, Amount::ZERO);
+    Ok(())
+}
+
+const DATA_PARTIAL: &str = r#"
+       type, client, tx, amount
+       deposit, 1, 1, 10
+       dispute, 1, 1, 4
+       dispute, 1, 1, 7
+       dispute, 1, 1, 3
+       resolve, 1, 1, 2
+       chargeback, 1, 1, 8
+       chargeback, 1, 1, 1
+       resolve, 1, 1,
+       dispute, 1, 1,
+       unlock, 1, 2,
+       dispute, 1, 1,
+       dispute, 1, 1,
+       resolve, 1, 1,
+       deposit, 2, 3, 5
+       withdrawal, 2, 4, 2
+       dispute, 2, 4, 0.5
+       chargeback, 2, 4,
+       dispute, 2, 3, 0
+       resolve, 2, 3, -1"#;
+
+/// check that disputes, resolves and chargebacks can move part of a tx, several times over
+#[test]
+pub fn test_partial_disputes() -> Result<()> {
+    let journal = Arc::new(Mutex::new(Vec::new()));
+    let sink = journal.clone();
+    let mut accounts =
+        Accounts::new().with_journal(Journal::new(move |events: &[Event]| -> Result<()> {
+            let mut w = sink.lock().unwrap();
+            for event in events {
+                serde_json::to_writer(&mut *w, event)?;
+                w.push(b'\n');
+            }
+            Ok(())
+        }));
+    let rows: Vec<_> = TransactionReader::new(DATA_PARTIAL.as_bytes())?.collect::<Result<_>>()?;
+    let results: Vec<_> = rows
+        .iter()
+        .filter_map(|row| row.transaction.as_ref().ok())
+        .map(|tx| accounts.process_transaction(tx))
+        .collect();
+    let units = Amount::from_units;
+    assert_eq!(
+        results,
+        vec![
+            Ok(Applied::Deposit(units(10_0000))),
+            Ok(Applied::Dispute(units(4_0000))),
+            Err(TxRejection::ExcessAmount {
+                client_id: 1,
+                tx_id: 1,
+                limit: units(6_0000)
+            }),
+            Ok(Applied::Dispute(units(3_0000))),
+            Ok(Applied::Resolve(units(2_0000))),
+            Err(TxRejection::ExcessAmount {
+                client_id: 1,
+                tx_id: 1,
+                limit: units(5_0000)
+            }),
+            Ok(Applied::Chargeback(units(1_0000))),
+            // the locked account can still settle the rest of the dispute, but not open another
+            Ok(Applied::Resolve(units(4_0000))),
+            Err(TxRejection::AccountLocked {
+                client_id: 1,
+                tx_id: 1
+            }),
+            Ok(Applied::Unlock),
+            // what is not charged back
+            Ok(Applied::Dispute(units(9_0000))),
+            Err(TxRejection::NotDisputable {
+                client_id: 1,
+                tx_id: 1
+            }),
+            Ok(Applied::Resolve(units(9_0000))),
+            Ok(Applied::Deposit(units(5_0000))),
+            Ok(Applied::Withdrawal(units(2_0000))),
+            Ok(Applied::Dispute(units(5000))),
+            Ok(Applied::Chargeback(units(5000))),
+        ]
+    );
+    // amounts must be positive
+    assert_eq!(
+        rows.iter().filter(|row| row.transaction.is_err()).count(),
+        2
+    );
+
+    let (one, two) = (&accounts.state[&1], &accounts.state[&2]);
+    assert_eq!(
+        (one.avail_bal, one.held_bal, one.total_bal, one.locked),
+        (units(9_0000), Amount::ZERO, units(9_0000), false)
+    );
+    assert!(one.disputes.is_empty());
+    assert_eq!(one.charged_back_txs, HashMap::from([(1, units(1_0000))]));
+    assert_eq!(
+        (two.avail_bal, two.held_bal, two.total_bal, two.locked),
+        (units(3_5000), Amount::ZERO, units(3_5000), true)
+    );
+    assert_eq!(two.charged_back, units(-5000));
+    assert_eq!(accounts.verify()?, vec![]);
+
+    let mut snapshot = Vec::new();
+    accounts.save_snapshot(&mut snapshot)?;
+    assert_eq!(
+        Accounts::new().load_snapshot(snapshot.as_slice())?,
+        accounts
+    );
+
+    let journal = journal.lock().unwrap().clone();
+    let rebuilt = Accounts::new().replay_journal(journal.as_slice())?;
+    assert_eq!(rebuilt.compare(&accounts)?, vec![]);
+
+    // a dispute left partly held
+    let mut accounts = Accounts::new();
+    for tx in &rows[..4] {
+        let _ = accounts.process_transaction(tx.transaction.as_ref().unwrap());
+    }
+    assert_eq!(
+        accounts.state[&1].disputes,
+        HashMap::from([(1, units(7_0000))])
+    );
+    assert_eq!(accounts.verify()?, vec![]);
+
+    // the second chargeback of a dispute does not lock the account again
+    let data = "type,client,tx,amount\n\
+        deposit,1,1,10\n\
+        dispute,1,1,\n\
+        chargeback,1,1,3\n\
+        chargeback,1,1,2\n";
+    let mut accounts = Accounts::new();
+    for row in TransactionReader::new(data.as_bytes())? {
+        accounts.process_transaction(row?.transaction.as_ref().unwrap())?;
+    }
+    let stats = accounts.summary().stats;
+    assert_eq!((stats.chargebacks, stats.accounts_locked), (2, 1));
+    Ok(())
+}
@@ -0,0 +1,553 @@
+use crate::parser::amount::Amount;
+use crate::parser::columns::{ColumnMapping, FIELDS};
+use crate::parser::compression::Compression;
+use crate::parser::report::Quarantine;
+use crate::parser::transactions::{Transaction, TransactionType};
+use anyhow::Result;
+use csv::{ByteRecord, Position, Reader, ReaderBuilder, Trim};
+use encoding_rs::Encoding;
+use encoding_rs_io::{DecodeReaderBytes, DecodeReaderBytesBuilder};
+use serde::de::{self, IntoDeserializer};
+use serde::Deserialize;
+use serde_json::value::RawValue;
+use std::io::{self, BufRead, BufReader, Chain, Cursor, Read};
+use std::path::Path;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// Reasons a row of the input could not be turned into a transaction.
+#[derive(Error, Debug)]
+pub enum RowError {
+    #[error(transparent)]
+    Malformed(#[from] csv::Error),
+    #[error(transparent)]
+    InvalidJson(#[from] serde_json::Error),
+    /// the value of column `field` could not be parsed
+    #[error("invalid {field}: {reason}")]
+    InvalidField { field: String, reason: String },
+    #[error("amount does not match transaction type")]
+    InvalidState,
+    #[error("reason does not match transaction type")]
+    InvalidReason,
+}
+
+/// What to do with rows that are malformed or fail `Transaction::check_state`.
+/// Either way the row is passed to the caller's rejection callback first.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ParseMode {
+    /// stop processing with an error
+    #[default]
+    Strict,
+    /// skip the row and carry on
+    Lenient,
+    /// skip the row and copy it to the quarantine of its reader,
+    /// see [`TransactionReader::with_quarantine`]
+    Quarantine,
+}
+
+impl FromStr for ParseMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "strict" => Ok(ParseMode::Strict),
+            "lenient" => Ok(ParseMode::Lenient),
+            "quarantine" => Ok(ParseMode::Quarantine),
+            _ => anyhow::bail!(
+                "unknown parse mode {:?}, expected strict, lenient or quarantine",
+                s
+            ),
+        }
+    }
+}
+
+/// Formats transactions can be read from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum InputFormat {
+    /// csv with a header row
+    #[default]
+    Csv,
+    /// one JSON object per line, with the same fields as the csv columns.
+    /// Amounts may be strings or numbers; either way they are parsed from the text as written.
+    JsonLines,
+}
+
+impl InputFormat {
+    /// the format implied by the extension of `path`: `.jsonl` or `.ndjson` for JSON Lines,
+    /// `.csv` for csv. A compression extension after it, like `.gz`, is skipped
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let extension = Compression::strip_extension(path)
+            .extension()?
+            .to_str()?
+            .to_ascii_lowercase();
+        match extension.as_str() {
+            "csv" => Some(InputFormat::Csv),
+            "jsonl" | "ndjson" => Some(InputFormat::JsonLines),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for InputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "csv" => Ok(InputFormat::Csv),
+            "jsonl" | "ndjson" => Ok(InputFormat::JsonLines),
+            _ => anyhow::bail!("unknown input format {:?}, expected csv or jsonl", s),
+        }
+    }
+}
+
+/// Delimiters tried, in order of preference, when detecting the delimiter of a csv input.
+pub const DETECTED_DELIMITERS: [u8; 4] = [b',', b';', b'\t', b'|'];
+
+/// How to read an input.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReaderOptions {
+    pub format: InputFormat,
+    /// which csv columns hold which fields
+    pub columns: ColumnMapping,
+    /// csv field delimiter, or `None` to detect it from the first line
+    pub delimiter: Option<u8>,
+    /// csv quote character
+    pub quote: u8,
+    /// skip csv lines starting with this byte
+    pub comment: Option<u8>,
+    /// character encoding of the input, or `None` for UTF-8
+    pub encoding: Option<&'static Encoding>,
+    /// drop a byte order mark at the start of the input. Without an explicit encoding,
+    /// a UTF-16 byte order mark also sets the encoding.
+    pub strip_bom: bool,
+}
+
+impl Default for ReaderOptions {
+    fn default() -> Self {
+        ReaderOptions {
+            format: InputFormat::default(),
+            columns: ColumnMapping::default(),
+            delimiter: None,
+            quote: b'"',
+            comment: None,
+            encoding: None,
+            strip_bom: true,
+        }
+    }
+}
+
+/// One row of the input.
+#[derive(Debug)]
+pub struct Row {
+    /// the record as read, whitespace around fields included.
+    /// A JSON Lines row is a single field holding the whole line.
+    pub raw: ByteRecord,
+    /// the row exactly as written in the input, decoded to UTF-8, without its line ending
+    pub text: Vec<u8>,
+    /// the format the row was read in
+    pub format: InputFormat,
+    /// the parsed transaction, or why it could not be parsed
+    pub transaction: Result<Transaction, RowError>,
+}
+
+impl Row {
+    /// line of the input the row started on
+    pub fn line(&self) -> u64 {
+        self.raw.position().map_or(0, |p| p.line())
+    }
+}
+
+/// Reads transactions from csv or JSON Lines.
+/// In csv, whitespace around fields is trimmed and rows may have trailing empty fields.
+/// Columns are found by header, ignoring case, or as set by a [`ColumnMapping`].
+pub struct TransactionReader<R> {
+    source: Source<R>,
+    // the header row exactly as written, if the input has one
+    header: Option<Vec<u8>>,
+    // the encoding given in the options, which quarantined rows are written back in
+    encoding: Option<&'static Encoding>,
+    quarantine: Option<Quarantine>,
+}
+
+/// the input, decoded to UTF-8
+type Decoded<R> = BufReader<DecodeReaderBytes<R, Vec<u8>>>;
+/// the lines read to detect the delimiter, then the rest of the input, recorded as it is read
+type CsvInput<R> = Recorder<Chain<Cursor<Vec<u8>>, Decoded<R>>>;
+
+enum Source<R> {
+    Csv {
+        rdr: Reader<CsvInput<R>>,
+        comment: Option<u8>,
+        // the canonical name of every column, see `ColumnMapping::canonical_headers`
+        headers: ByteRecord,
+    },
+    JsonLines {
+        lines: Decoded<R>,
+        // number of lines read so far
+        line: u64,
+    },
+}
+
+/// Keeps what the csv reader has read since the start of the current record,
+/// so every row can also be had exactly as written.
+struct Recorder<R> {
+    inner: R,
+    buf: Vec<u8>,
+    // offset in the input of the start of `buf`
+    start: u64,
+}
+
+impl<R: Read> Read for Recorder<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(out)?;
+        self.buf.extend_from_slice(&out[..n]);
+        Ok(n)
+    }
+}
+
+impl<R> Recorder<R> {
+    /// the text of the record between offsets `from` and `to` of the input, and the number of
+    /// lines skipped before it (see `record_text`), forgetting everything before `to`
+    fn take(&mut self, from: u64, to: u64, comment: Option<u8>) -> (u64, Vec<u8>) {
+        let offset = |at: u64| (at.saturating_sub(self.start) as usize).min(self.buf.len());
+        let (from, to) = (offset(from), offset(to));
+        let text = record_text(&self.buf[from..to], comment);
+        self.buf.drain(..to);
+        self.start += to as u64;
+        text
+    }
+}
+
+/// `text` without the comment and blank lines the csv reader skipped before the record,
+/// or the line ending after it, and the number of lines skipped.
+/// After a `\r\n` line ending the csv reader starts the next record at the `\n`,
+/// which counts as a skipped line too.
+fn record_text(mut text: &[u8], comment: Option<u8>) -> (u64, Vec<u8>) {
+    let mut skipped = 0;
+    while let Some(&first) = text.first() {
+        if first != b'\n' && first != b'\r' && Some(first) != comment {
+            break;
+        }
+        text = match text.iter().position(|&b| b == b'\n') {
+            Some(end) => &text[end + 1..],
+            None => &[],
+        };
+        skipped += 1;
+    }
+    let text = text.strip_suffix(b"\n").unwrap_or(text);
+    (skipped, text.strip_suffix(b"\r").unwrap_or(text).to_vec())
+}
+
+/// wraps `r` to decode it from `options.encoding`, leaving UTF-8 as it is
+fn decode<R: Read>(r: R, options: &ReaderOptions) -> Decoded<R> {
+    BufReader::new(
+        DecodeReaderBytesBuilder::new()
+            .encoding(options.encoding)
+            .utf8_passthru(true)
+            .bom_sniffing(options.strip_bom)
+            .strip_bom(options.strip_bom)
+            .build(r),
+    )
+}
+
+/// the one of `DETECTED_DELIMITERS` found most often outside quotes in the first line of `line`,
+/// or a comma if there are none
+pub fn detect_delimiter(line: &[u8], quote: u8) -> u8 {
+    let mut counts = [0; DETECTED_DELIMITERS.len()];
+    let mut quoted = false;
+    for &b in line {
+        if b == quote {
+            quoted = !quoted;
+        } else if !quoted && (b == b'\n' || b == b'\r') {
+            break;
+        } else if !quoted {
+            if let Some(i) = DETECTED_DELIMITERS.iter().position(|&d| d == b) {
+                counts[i] += 1;
+            }
+        }
+    }
+    let mut best = 0;
+    for i in 1..counts.len() {
+        if counts[i] > counts[best] {
+            best = i;
+        }
+    }
+    DETECTED_DELIMITERS[best]
+}
+
+/// One line of JSON Lines input, every field kept as written.
+#[derive(Deserialize)]
+struct JsonRow<'a> {
+    #[serde(rename = "type", borrow, default)]
+    transaction_type: Option<&'a RawValue>,
+    #[serde(borrow, default)]
+    client: Option<&'a RawValue>,
+    #[serde(borrow, default)]
+    tx: Option<&'a RawValue>,
+    #[serde(borrow, default)]
+    amount: Option<&'a RawValue>,
+    #[serde(borrow, default)]
+    reason: Option<&'a RawValue>,
+}
+
+impl<R: Read> TransactionReader<R> {
+    /// reads the header row of the csv `r`
+    pub fn new(r: R) -> Result<Self> {
+        Self::csv(r, &ColumnMapping::default())
+    }
+
+    /// reads the csv `r`, finding its columns with `columns`
+    pub fn csv(r: R, columns: &ColumnMapping) -> Result<Self> {
+        Self::with_options(
+            r,
+            &ReaderOptions {
+                columns: columns.clone(),
+                ..ReaderOptions::default()
+            },
+        )
+    }
+
+    fn csv_with_options(r: R, options: &ReaderOptions) -> Result<Self> {
+        let mut input = decode(r, options);
+        let mut head = vec![];
+        let delimiter = match options.delimiter {
+            Some(delimiter) => delimiter,
+            None => {
+                // the first line that is not a comment
+                let mut start = 0;
+                while input.read_until(b'\n', &mut head)? > 0
+                    && options.comment.is_some()
+                    && head.get(start).copied() == options.comment
+                {
+                    start = head.len();
+                }
+                detect_delimiter(&head[start..], options.quote)
+            }
+        };
+        let columns = &options.columns;
+        // records are trimmed per row so `Row::raw` keeps them verbatim
+        let mut rdr = ReaderBuilder::new()
+            .trim(Trim::Headers)
+            .delimiter(delimiter)
+            .quote(options.quote)
+            .comment(options.comment)
+            .flexible(true)
+            .has_headers(columns.has_headers())
+            .from_reader(Recorder {
+                inner: Cursor::new(head).chain(input),
+                buf: vec![],
+                start: 0,
+            });
+        let headers = if columns.has_headers() {
+            columns.canonical_headers(Some(rdr.byte_headers()?))?
+        } else {
+            columns.canonical_headers(None)?
+        };
+        // the header row is not a row of the input
+        let end = rdr.position().byte();
+        let (_, header) = rdr.get_mut().take(0, end, options.comment);
+        Ok(TransactionReader {
+            source: Source::Csv {
+                rdr,
+                comment: options.comment,
+                headers,
+            },
+            header: columns.has_headers().then_some(header),
+            encoding: options.encoding,
+            quarantine: None,
+        })
+    }
+
+    /// reads JSON Lines from `r`
+    pub fn json_lines(r: R) -> Self {
+        Self::json_lines_with_options(r, &ReaderOptions::default())
+    }
+
+    fn json_lines_with_options(r: R, options: &ReaderOptions) -> Self {
+        TransactionReader {
+            source: Source::JsonLines {
+                lines: decode(r, options),
+                line: 0,
+            },
+            header: None,
+            encoding: options.encoding,
+            quarantine: None,
+        }
+    }
+
+    /// reads `r` in `format`
+    pub fn with_format(r: R, format: InputFormat) -> Result<Self> {
+        Self::with_options(
+            r,
+            &ReaderOptions {
+                format,
+                ..ReaderOptions::default()
+            },
+        )
+    }
+
+    /// reads `r` as set by `options`
+    pub fn with_options(r: R, options: &ReaderOptions) -> Result<Self> {
+        match options.format {
+            InputFormat::Csv => Self::csv_with_options(r, options),
+            InputFormat::JsonLines => Ok(Self::json_lines_with_options(r, options)),
+        }
+    }
+
+    /// copies the rows passed to [`TransactionReader::quarantine`] to `quarantine`,
+    /// after the header row of the input and in the encoding given in its options
+    pub fn with_quarantine(self, mut quarantine: Quarantine) -> Self {
+        quarantine.set_input(self.header.clone(), self.encoding);
+        TransactionReader {
+            quarantine: Some(quarantine),
+            ..self
+        }
+    }
+
+    /// copies `row`, read by this reader, to its quarantine exactly as written.
+    /// Fails if there is no quarantine
+    pub fn quarantine(&mut self, row: &Row) -> Result<()> {
+        match self.quarantine.as_mut() {
+            Some(quarantine) => quarantine.record(row),
+            None => anyhow::bail!("line {}: no quarantine to copy the row to", row.line()),
+        }
+    }
+
+    /// reads the next row; `None` at the end of the input.
+    /// Errors only if the input itself can't be read; a bad row is reported in `Row::transaction`.
+    pub fn next_row(&mut self) -> Result<Option<Row>> {
+        match &mut self.source {
+            Source::Csv {
+                rdr,
+                comment,
+                headers,
+            } => {
+                let mut raw = ByteRecord::new();
+                if !rdr.read_byte_record(&mut raw)? {
+                    return Ok(None);
+                }
+                let mut position = raw.position().cloned().unwrap_or_else(Position::new);
+                let end = rdr.position().byte();
+                let (skipped, text) = rdr.get_mut().take(position.byte(), end, *comment);
+                // the line the record itself starts on
+                position.set_line(position.line() + skipped);
+                raw.set_position(Some(position));
+                let mut trimmed = raw.clone();
+                trimmed.trim();
+                Ok(Some(Row {
+                    transaction: parse_record(headers, &trimmed),
+                    format: InputFormat::Csv,
+                    raw,
+                    text,
+                }))
+            }
+            Source::JsonLines { lines, line } => {
+                let mut buf = String::new();
+                loop {
+                    buf.clear();
+                    if lines.read_line(&mut buf)? == 0 {
+                        return Ok(None);
+                    }
+                    *line += 1;
+                    if !buf.trim().is_empty() {
+                        break;
+                    }
+                }
+                let text = buf.trim_end_matches(['\n', '\r']);
+                let mut position = Position::new();
+                position.set_line(*line);
+                let mut raw = ByteRecord::from(vec![text]);
+                raw.set_position(Some(position));
+                Ok(Some(Row {
+                    transaction: parse_json(text),
+                    format: InputFormat::JsonLines,
+                    raw,
+                    text: text.as_bytes().to_vec(),
+                }))
+            }
+        }
+    }
+}
+
+/// deserializes a (trimmed) csv record and checks that its amount and reason match its type
+fn parse_record(headers: &ByteRecord, record: &ByteRecord) -> Result<Transaction, RowError> {
+    let tx = record
+        .deserialize::<Transaction>(Some(headers))
+        .map_err(|err| row_error(headers, record, err))?;
+    if !tx.check_amount() {
+        Err(RowError::InvalidState)
+    } else if !tx.check_reason() {
+        Err(RowError::InvalidReason)
+    } else {
+        Ok(tx)
+    }
+}
+
+/// parses a line of JSON Lines by turning it into a csv record,
+/// so fields are parsed and reported exactly like csv columns
+fn parse_json(line: &str) -> Result<Transaction, RowError> {
+    let row: JsonRow = serde_json::from_str(line)?;
+    let mut record = ByteRecord::new();
+    for field in [
+        row.transaction_type,
+        row.client,
+        row.tx,
+        row.amount,
+        row.reason,
+    ] {
+        let text = match field.map(RawValue::get) {
+            None | Some("null") => String::new(),
+            Some(quoted) if quoted.starts_with('"') => serde_json::from_str(quoted)?,
+            Some(text) => text.to_string(),
+        };
+        record.push_field(text.as_bytes());
+    }
+    parse_record(&ByteRecord::from(FIELDS.to_vec()), &record)
+}
+
+/// finds the column of `record` that made its deserialization fail.
+/// csv does not say for most errors, so every column is parsed again on its own.
+fn row_error(headers: &ByteRecord, record: &ByteRecord, err: csv::Error) -> RowError {
+    for (idx, name) in headers.iter().enumerate() {
+        let name = String::from_utf8_lossy(name);
+        let value = record.get(idx).map(String::from_utf8_lossy);
+        if let Some(reason) = invalid_field(&name, value.as_deref()) {
+            return RowError::InvalidField {
+                field: name.into_owned(),
+                reason,
+            };
+        }
+    }
+    RowError::Malformed(err)
+}
+
+/// why `value` is not valid for the column `name`, if it is not
+fn invalid_field(name: &str, value: Option<&str>) -> Option<String> {
+    let value = match (name, value) {
+        ("amount" | "reason", None | Some("")) => return None,
+        ("type" | "client" | "tx", None) => return Some("missing value".to_string()),
+        (_, None) => return None,
+        (_, Some(value)) => value,
+    };
+    match name {
+        "type" => TransactionType::deserialize(value.into_deserializer())
+            .err()
+            .map(|err: de::value::Error| err.to_string()),
+        "client" => value.parse::<u16>().err().map(|err| err.to_string()),
+        "tx" => value.parse::<u32>().err().map(|err| err.to_string()),
+        "amount" => value
+            .parse::<Amount>()
+            .err()
+            .map(|err| format!("{:?}: {}", value, err)),
+        "reason" => value.parse::<u32>().err().map(|err| err.to_string()),
+        _ => None,
+    }
+}
+
+impl<R: Read> Iterator for TransactionReader<R> {
+    type Item = Result<Row>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_row().transpose()
+    }
+}
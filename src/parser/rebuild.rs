@@ -0,0 +1,333 @@
+use crate::parser::accounts::{sorted_by_tx, Account, Accounts};
+use crate::parser::amount::Amount;
+use crate::parser::journal::{Balances, Event, EventKind};
+use crate::parser::store::TxStore;
+use crate::parser::transactions::{Transaction, TransactionType};
+use anyhow::{anyhow, bail, Context, Result};
+use csv::{ReaderBuilder, Trim};
+use serde::Deserialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{BufRead, Read};
+use thiserror::Error;
+
+/// A difference between accounts rebuilt from a journal and the state they are checked against.
+#[derive(Error, Debug, Clone, Eq, PartialEq)]
+pub enum Mismatch {
+    #[error("client {client_id}: expected, but not in the journal")]
+    MissingAccount { client_id: u16 },
+    #[error("client {client_id}: in the journal, but not expected")]
+    UnexpectedAccount { client_id: u16 },
+    #[error("client {client_id}: journal has {journal}, expected {expected}")]
+    Balances {
+        client_id: u16,
+        journal: Balances,
+        expected: Balances,
+    },
+    #[error("client {client_id}: journal has open disputes {journal:?}, expected {expected:?}")]
+    Disputes {
+        client_id: u16,
+        journal: Vec<(u32, Amount)>,
+        expected: Vec<(u32, Amount)>,
+    },
+    #[error("client {client_id}: journal has charged back txs {journal:?}, expected {expected:?}")]
+    ChargedBackTxs {
+        client_id: u16,
+        journal: Vec<(u32, Amount)>,
+        expected: Vec<(u32, Amount)>,
+    },
+    #[error("client {client_id}: journal has {journal} charged back, expected {expected}")]
+    ChargedBack {
+        client_id: u16,
+        journal: Amount,
+        expected: Amount,
+    },
+    #[error("client {client_id}: journal has closed {journal}, expected {expected}")]
+    Closed {
+        client_id: u16,
+        journal: bool,
+        expected: bool,
+    },
+    #[error("client {client_id}: tx {tx_id} differs from the expected history")]
+    History { client_id: u16, tx_id: u32 },
+    #[error("client {client_id}: tx id {tx_id} is used up in only one of the states")]
+    SeenTx { client_id: u16, tx_id: u32 },
+}
+
+/// One row of the accounts csv written by `Accounts::serialize_to_writer_sorted`.
+#[derive(Deserialize)]
+struct AccountRow {
+    client: u16,
+    available: Amount,
+    held: Amount,
+    total: Amount,
+    locked: bool,
+}
+
+/// reads the balances of every account from the csv written by the `process` command
+pub fn read_accounts_csv(r: impl Read) -> Result<BTreeMap<u16, Balances>> {
+    let mut rdr = ReaderBuilder::new().trim(Trim::All).from_reader(r);
+    let mut accounts = BTreeMap::new();
+    for (idx, row) in rdr.deserialize().enumerate() {
+        // header is line 1
+        let row: AccountRow = row.with_context(|| format!("accounts line {}", idx + 2))?;
+        let balances = Balances {
+            available: row.available,
+            held: row.held,
+            total: row.total,
+            locked: row.locked,
+        };
+        if accounts.insert(row.client, balances).is_some() {
+            bail!("accounts line {}: client {} repeated", idx + 2, row.client);
+        }
+    }
+    Ok(accounts)
+}
+
+impl<S: TxStore> Accounts<S> {
+    /// rebuilds these accounts by applying every event of the journal read from `r`,
+    /// see `Accounts::apply_event`
+    pub fn replay_journal(mut self, r: impl BufRead) -> Result<Self> {
+        for (idx, line) in r.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let event: Event =
+                serde_json::from_str(&line).with_context(|| format!("journal line {}", idx + 1))?;
+            self.apply_event(&event)
+                .with_context(|| format!("journal line {}", idx + 1))?;
+        }
+        Ok(self)
+    }
+
+    /// applies one journal event: balances become those after it, and the history,
+    /// open disputes and used up tx ids change as they did when it was recorded.
+    /// Fails unless the balances before it are the account's current ones,
+    /// i.e. the journal is complete and in order.
+    pub fn apply_event(&mut self, event: &Event) -> Result<()> {
+        let (client_id, tx_id) = (event.client, event.tx);
+        if event.event == EventKind::Opened {
+            if self.state.contains_key(&client_id) {
+                bail!("client {} is opened twice", client_id);
+            }
+            self.touch(client_id);
+            return Ok(());
+        }
+        let account = self.state.get_mut(&client_id).ok_or_else(|| {
+            anyhow!(
+                "tx {}: client {} was never opened; the journal is missing its start",
+                tx_id,
+                client_id
+            )
+        })?;
+        if Balances::of(account) != event.before {
+            bail!(
+                "tx {} of client {}: the journal has {} before it, but the account has {}",
+                tx_id,
+                client_id,
+                event.before,
+                Balances::of(account)
+            );
+        }
+        match event.event {
+            EventKind::Opened => unreachable!("handled above"),
+            EventKind::Credited | EventKind::Debited | EventKind::Adjusted => {
+                let transaction_type = match event.event {
+                    EventKind::Credited => TransactionType::Deposit,
+                    EventKind::Debited => TransactionType::Withdrawal,
+                    _ => TransactionType::Adjust,
+                };
+                let amount = event
+                    .amount
+                    .ok_or_else(|| anyhow!("tx {} of client {} has no amount", tx_id, client_id))?;
+                self.seen_tx_ids.insert(client_id, tx_id);
+                self.store.insert(Transaction {
+                    transaction_type,
+                    client_id,
+                    tx_id,
+                    amount: Some(amount),
+                    reason: event.reason,
+                })?;
+            }
+            EventKind::Held => {
+                let held = account.disputes.entry(tx_id).or_default();
+                *held = event
+                    .amount
+                    .and_then(|amount| held.checked_add(amount))
+                    .ok_or_else(|| anyhow!("tx {} of client {} has no amount", tx_id, client_id))?;
+            }
+            EventKind::Released => {
+                release(account, event)?;
+            }
+            EventKind::ChargedBack => {
+                let amount = release(account, event)?;
+                let tx_charged_back = account.charged_back_txs.entry(tx_id).or_default();
+                *tx_charged_back = tx_charged_back
+                    .checked_add(amount)
+                    .ok_or_else(|| anyhow!("balances of client {} overflow", client_id))?;
+                // whatever left the total was charged back
+                account.charged_back = event
+                    .before
+                    .total
+                    .checked_sub(event.after.total)
+                    .and_then(|removed| account.charged_back.checked_add(removed))
+                    .ok_or_else(|| anyhow!("balances of client {} overflow", client_id))?;
+            }
+            // a chargeback's lock carries the tx id its charged back transaction already claimed
+            EventKind::Locked | EventKind::Unlocked | EventKind::Claimed => {
+                self.seen_tx_ids.insert(client_id, tx_id);
+            }
+            EventKind::Closed => {
+                self.seen_tx_ids.insert(client_id, tx_id);
+                account.closed = true;
+            }
+        }
+        set_balances(account, event.after);
+        Ok(())
+    }
+
+    /// compares the balances of every account with `expected`, e.g. read by `read_accounts_csv`
+    pub fn compare_balances(&self, expected: &BTreeMap<u16, Balances>) -> Vec<Mismatch> {
+        let mut mismatches = vec![];
+        let client_ids: BTreeSet<u16> = self.state.keys().chain(expected.keys()).copied().collect();
+        for client_id in client_ids {
+            match (self.state.get(&client_id), expected.get(&client_id)) {
+                (Some(account), Some(expected)) if Balances::of(account) != *expected => mismatches
+                    .push(Mismatch::Balances {
+                        client_id,
+                        journal: Balances::of(account),
+                        expected: *expected,
+                    }),
+                (Some(_), Some(_)) => {}
+                (None, _) => mismatches.push(Mismatch::MissingAccount { client_id }),
+                (_, None) => mismatches.push(Mismatch::UnexpectedAccount { client_id }),
+            }
+        }
+        mismatches
+    }
+
+    /// compares the complete state with `expected`, e.g. loaded from a snapshot:
+    /// balances, open disputes, closed accounts, charged back amounts and txs, transaction history
+    /// and used up tx ids
+    pub fn compare<T: TxStore>(&self, expected: &Accounts<T>) -> Result<Vec<Mismatch>> {
+        let expected_balances = expected
+            .state
+            .iter()
+            .map(|(client_id, account)| (*client_id, Balances::of(account)))
+            .collect();
+        let mut mismatches = self.compare_balances(&expected_balances);
+
+        let mut client_ids: Vec<u16> = self.state.keys().copied().collect();
+        client_ids.sort_unstable();
+        for client_id in client_ids {
+            let (account, other) = match expected.state.get(&client_id) {
+                Some(other) => (&self.state[&client_id], other),
+                None => continue,
+            };
+            let (disputes, expected_disputes) = (
+                sorted_by_tx(&account.disputes),
+                sorted_by_tx(&other.disputes),
+            );
+            if disputes != expected_disputes {
+                mismatches.push(Mismatch::Disputes {
+                    client_id,
+                    journal: disputes,
+                    expected: expected_disputes,
+                });
+            }
+            let (charged_back_txs, expected_charged_back_txs) = (
+                sorted_by_tx(&account.charged_back_txs),
+                sorted_by_tx(&other.charged_back_txs),
+            );
+            if charged_back_txs != expected_charged_back_txs {
+                mismatches.push(Mismatch::ChargedBackTxs {
+                    client_id,
+                    journal: charged_back_txs,
+                    expected: expected_charged_back_txs,
+                });
+            }
+            if account.closed != other.closed {
+                mismatches.push(Mismatch::Closed {
+                    client_id,
+                    journal: account.closed,
+                    expected: other.closed,
+                });
+            }
+            if account.charged_back != other.charged_back {
+                mismatches.push(Mismatch::ChargedBack {
+                    client_id,
+                    journal: account.charged_back,
+                    expected: other.charged_back,
+                });
+            }
+        }
+
+        let (history, expected_history) = (history(&self.store)?, history(&expected.store)?);
+        let tx_keys: BTreeSet<(u16, u32)> = history
+            .keys()
+            .chain(expected_history.keys())
+            .copied()
+            .collect();
+        for (client_id, tx_id) in tx_keys {
+            let key = (client_id, tx_id);
+            if history.get(&key) != expected_history.get(&key) {
+                mismatches.push(Mismatch::History { client_id, tx_id });
+            }
+        }
+
+        let seen: BTreeSet<(u16, u32)> = self.seen_tx_ids.sorted().into_iter().collect();
+        let expected_seen = expected.seen_tx_ids.sorted().into_iter().collect();
+        for (client_id, tx_id) in seen.symmetric_difference(&expected_seen) {
+            mismatches.push(Mismatch::SeenTx {
+                client_id: *client_id,
+                tx_id: *tx_id,
+            });
+        }
+        Ok(mismatches)
+    }
+}
+
+/// takes the amount of a resolve or chargeback `event` from the part of its tx held,
+/// closing the dispute once nothing is; returns the amount
+fn release(account: &mut Account, event: &Event) -> Result<Amount> {
+    let (client_id, tx_id) = (event.client, event.tx);
+    let amount = event
+        .amount
+        .ok_or_else(|| anyhow!("tx {} of client {} has no amount", tx_id, client_id))?;
+    let held = account
+        .disputes
+        .get(&tx_id)
+        .and_then(|held| held.checked_sub(amount))
+        .filter(|held| !held.is_negative())
+        .ok_or_else(|| {
+            anyhow!(
+                "tx {} of client {} releases {}, more than its dispute holds",
+                tx_id,
+                client_id,
+                amount
+            )
+        })?;
+    if held == Amount::ZERO {
+        account.disputes.remove(&tx_id);
+    } else {
+        account.disputes.insert(tx_id, held);
+    }
+    Ok(amount)
+}
+
+fn set_balances(account: &mut Account, balances: Balances) {
+    account.avail_bal = balances.available;
+    account.held_bal = balances.held;
+    account.total_bal = balances.total;
+    account.locked = balances.locked;
+}
+
+/// every stored transaction by client and tx id
+fn history(store: &impl TxStore) -> Result<BTreeMap<(u16, u32), Transaction>> {
+    let mut history = BTreeMap::new();
+    store.for_each(&mut |tx| {
+        history.insert((tx.client_id, tx.tx_id), tx);
+        Ok(())
+    })?;
+    Ok(history)
+}
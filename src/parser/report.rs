@@ -0,0 +1,121 @@
+use crate::parser::reader::Row;
+use anyhow::Result;
+use csv::{Writer, WriterBuilder};
+use encoding_rs::{Encoding, UTF_16BE, UTF_16LE};
+use serde::Serialize;
+use std::fmt::Display;
+use std::io::Write;
+
+/// One row of the rejection report.
+#[derive(Serialize, Debug, Clone, Eq, PartialEq)]
+pub struct RejectedRow {
+    /// the input the record was read from
+    pub input: String,
+    /// line of the input the record started on
+    pub line: u64,
+    /// the record exactly as written in the input
+    pub record: String,
+    pub reason: String,
+}
+
+/// Writes one CSV row per rejected or malformed input record.
+pub struct RejectionReport<W: Write> {
+    wtr: Writer<W>,
+    input: String,
+}
+
+impl<W: Write> RejectionReport<W> {
+    pub fn new(w: W) -> Self {
+        RejectionReport {
+            wtr: WriterBuilder::new().delimiter(b',').from_writer(w),
+            input: String::new(),
+        }
+    }
+
+    /// names the input that the following records are read from
+    pub fn set_input(&mut self, input: impl Into<String>) {
+        self.input = input.into();
+    }
+
+    /// appends `row` to the report along with the reason it was rejected
+    pub fn record(&mut self, row: &Row, reason: &dyn Display) -> Result<()> {
+        let rejected = RejectedRow {
+            input: self.input.clone(),
+            line: row.line(),
+            record: String::from_utf8_lossy(&row.text).into_owned(),
+            reason: reason.to_string(),
+        };
+        self.wtr.serialize(rejected)?;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.wtr.flush()?;
+        Ok(())
+    }
+}
+
+/// Copies the malformed rows of one input to a side file exactly as they were written,
+/// after the input's header row and in its encoding, so they can be fixed and fed again
+/// with the same options. An encoding only found from a byte order mark is not kept:
+/// those rows are written as UTF-8.
+/// Attach it to the reader of the input with `TransactionReader::with_quarantine`.
+/// Every row is flushed as it is written.
+pub struct Quarantine {
+    w: Box<dyn Write + Send>,
+    // the header row of the input, until it is written before the first row
+    header: Option<Vec<u8>>,
+    // the encoding of the input, or `None` for UTF-8
+    encoding: Option<&'static Encoding>,
+}
+
+impl Quarantine {
+    pub fn new(w: impl Write + Send + 'static) -> Self {
+        Quarantine {
+            w: Box::new(w),
+            header: None,
+            encoding: None,
+        }
+    }
+
+    /// writes `header` before the first row, if any, and every line in `encoding`
+    pub(crate) fn set_input(
+        &mut self,
+        header: Option<Vec<u8>>,
+        encoding: Option<&'static Encoding>,
+    ) {
+        self.header = header;
+        self.encoding = encoding;
+    }
+
+    /// appends `row` to the quarantine
+    pub fn record(&mut self, row: &Row) -> Result<()> {
+        if let Some(header) = self.header.take() {
+            self.write_line(&header)?;
+        }
+        self.write_line(&row.text)?;
+        self.w.flush()?;
+        Ok(())
+    }
+
+    /// writes `line`, read as UTF-8, and a line ending in the encoding of the input
+    fn write_line(&mut self, line: &[u8]) -> Result<()> {
+        let line = format!("{}\n", String::from_utf8_lossy(line));
+        match self.encoding {
+            // encoding_rs only encodes to ASCII-compatible encodings
+            Some(encoding) if encoding == UTF_16LE => {
+                for unit in line.encode_utf16() {
+                    self.w.write_all(&unit.to_le_bytes())?;
+                }
+            }
+            Some(encoding) if encoding == UTF_16BE => {
+                for unit in line.encode_utf16() {
+                    self.w.write_all(&unit.to_be_bytes())?;
+                }
+            }
+            Some(encoding) => self.w.write_all(&encoding.encode(&line).0)?,
+            None => self.w.write_all(line.as_bytes())?,
+        }
+        Ok(())
+    }
+}
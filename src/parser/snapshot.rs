@@ -0,0 +1,137 @@
+use crate::parser::accounts::{sorted_by_tx, Account, Accounts, SortKey};
+use crate::parser::amount::Amount;
+use crate::parser::store::TxStore;
+use crate::parser::transactions::Transaction;
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::io::{BufRead, Write};
+
+/// Version of the snapshot format written by `Accounts::save_snapshot`.
+/// Bump whenever the format changes.
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+const SNAPSHOT_FORMAT: &str = "toy_txs-snapshot";
+
+/// One line of a snapshot file.
+/// A snapshot is JSON Lines: a header, then every account, then every stored transaction,
+/// then every seen tx id as `[client, tx]`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Entry {
+    Header { format: String, version: u32 },
+    Account(AccountState),
+    Tx(Transaction),
+    Seen(u16, u32),
+}
+
+/// Full state of one account, including what is skipped in the csv output.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct AccountState {
+    client: u16,
+    available: Amount,
+    held: Amount,
+    total: Amount,
+    locked: bool,
+    /// the disputed tx ids, with the part of each tx that is held
+    disputes: Vec<(u32, Amount)>,
+    charged_back: Amount,
+    /// the charged back tx ids, with the part of each tx that was charged back
+    charged_back_txs: Vec<(u32, Amount)>,
+    closed: bool,
+}
+
+impl<S: TxStore> Accounts<S> {
+    /// writes the complete state, including transaction history and open disputes, to `w`
+    pub fn save_snapshot(&self, mut w: impl Write) -> Result<()> {
+        write_entry(
+            &mut w,
+            &Entry::Header {
+                format: SNAPSHOT_FORMAT.to_string(),
+                version: SNAPSHOT_VERSION,
+            },
+        )?;
+        for account in self.sorted(SortKey::ClientId) {
+            let disputes = sorted_by_tx(&account.disputes);
+            let charged_back_txs = sorted_by_tx(&account.charged_back_txs);
+            write_entry(
+                &mut w,
+                &Entry::Account(AccountState {
+                    client: account.client_id,
+                    available: account.avail_bal,
+                    held: account.held_bal,
+                    total: account.total_bal,
+                    locked: account.locked,
+                    disputes,
+                    charged_back: account.charged_back,
+                    charged_back_txs,
+                    closed: account.closed,
+                }),
+            )?;
+        }
+        self.store
+            .for_each(&mut |tx| write_entry(&mut w, &Entry::Tx(tx)))?;
+        for (client_id, tx_id) in self.seen_tx_ids.sorted() {
+            write_entry(&mut w, &Entry::Seen(client_id, tx_id))?;
+        }
+        w.flush()?;
+        Ok(())
+    }
+
+    /// restores a snapshot written by `save_snapshot` into these (empty) accounts,
+    /// loading its transaction history into the tx store
+    pub fn load_snapshot(mut self, r: impl BufRead) -> Result<Self> {
+        if !self.state.is_empty() {
+            bail!("a snapshot can only be loaded into empty accounts");
+        }
+        let mut lines = r.lines();
+        let header = lines.next().ok_or_else(|| anyhow!("snapshot is empty"))??;
+        match serde_json::from_str(&header) {
+            Ok(Entry::Header { format, version }) if format == SNAPSHOT_FORMAT => {
+                if version != SNAPSHOT_VERSION {
+                    bail!(
+                        "unsupported snapshot version {}, expected {}",
+                        version,
+                        SNAPSHOT_VERSION
+                    );
+                }
+            }
+            _ => bail!("not a snapshot: missing header"),
+        }
+        for (idx, line) in lines.enumerate() {
+            let line = line?;
+            // header is line 1
+            let entry = serde_json::from_str(&line)
+                .with_context(|| format!("snapshot line {}", idx + 2))?;
+            match entry {
+                Entry::Header { .. } => bail!("snapshot line {}: unexpected header", idx + 2),
+                Entry::Account(state) => {
+                    self.state.insert(
+                        state.client,
+                        Account {
+                            client_id: state.client,
+                            avail_bal: state.available,
+                            held_bal: state.held,
+                            total_bal: state.total,
+                            locked: state.locked,
+                            disputes: state.disputes.into_iter().collect(),
+                            charged_back_txs: state.charged_back_txs.into_iter().collect(),
+                            charged_back: state.charged_back,
+                            closed: state.closed,
+                        },
+                    );
+                }
+                Entry::Tx(tx) => self.store.insert(tx)?,
+                Entry::Seen(client_id, tx_id) => {
+                    self.seen_tx_ids.insert(client_id, tx_id);
+                }
+            }
+        }
+        Ok(self)
+    }
+}
+
+fn write_entry(w: &mut impl Write, entry: &Entry) -> Result<()> {
+    serde_json::to_writer(&mut *w, entry)?;
+    w.write_all(b"\n")?;
+    Ok(())
+}
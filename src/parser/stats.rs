@@ -0,0 +1,192 @@
+use crate::parser::accounts::{Applied, TxRejection};
+use crate::parser::amount::Amount;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Counts of what happened to the transactions of a run, kept by
+/// `Accounts::process_transaction`. Not part of the state: snapshots and equality ignore them.
+#[derive(Serialize, Debug, Clone, Default, Eq, PartialEq)]
+pub struct Stats {
+    /// transactions processed, applied or not
+    pub transactions: u64,
+    /// rows that could not be parsed into a transaction, counted by `Ledger`
+    pub malformed: u64,
+    pub applied: u64,
+    pub rejected: u64,
+    /// rejected transactions by `TxRejection::kind`
+    pub rejected_by_reason: BTreeMap<&'static str, u64>,
+    pub deposits: u64,
+    pub deposited: Amount,
+    pub withdrawals: u64,
+    pub withdrawn: Amount,
+    pub disputes_opened: u64,
+    pub disputes_resolved: u64,
+    pub chargebacks: u64,
+    pub charged_back: Amount,
+    pub adjustments: u64,
+    /// net signed amount of the adjustments
+    pub adjusted: Amount,
+    pub accounts_created: u64,
+    /// times an unlocked account was locked, by a chargeback or an admin
+    pub accounts_locked: u64,
+    pub accounts_unlocked: u64,
+    pub accounts_closed: u64,
+    /// transactions rejected because a balance would overflow
+    pub overflows: u64,
+    /// money added to total balances: deposits, positive adjustments
+    /// and chargebacks of withdrawals
+    pub money_in: Amount,
+    /// money taken from total balances: withdrawals, negative adjustments
+    /// and chargebacks of deposits
+    pub money_out: Amount,
+}
+
+impl Stats {
+    /// counts the outcome of a transaction; `created` if it opened its account, `locked` if it
+    /// locked an unlocked account, and `moved` is the signed change of its total balance
+    pub fn record(
+        &mut self,
+        result: &Result<Applied, TxRejection>,
+        created: bool,
+        locked: bool,
+        moved: Amount,
+    ) {
+        self.transactions += 1;
+        if created {
+            self.accounts_created += 1;
+        }
+        // a partial chargeback leaves the account locked for the next one
+        if locked {
+            self.accounts_locked += 1;
+        }
+        if moved.is_negative() {
+            // cannot overflow, `moved` is the amount of a transaction
+            let out = Amount::ZERO.checked_sub(moved).unwrap_or(Amount::ZERO);
+            self.money_out = self.money_out.saturating_add(out);
+        } else {
+            self.money_in = self.money_in.saturating_add(moved);
+        }
+        match result {
+            Ok(applied) => {
+                self.applied += 1;
+                match *applied {
+                    Applied::Deposit(amount) => {
+                        self.deposits += 1;
+                        self.deposited = self.deposited.saturating_add(amount);
+                    }
+                    Applied::Withdrawal(amount) => {
+                        self.withdrawals += 1;
+                        self.withdrawn = self.withdrawn.saturating_add(amount);
+                    }
+                    Applied::Dispute(_) => self.disputes_opened += 1,
+                    Applied::Resolve(_) => self.disputes_resolved += 1,
+                    Applied::Chargeback(amount) => {
+                        self.chargebacks += 1;
+                        self.charged_back = self.charged_back.saturating_add(amount);
+                    }
+                    Applied::Adjustment(amount) => {
+                        self.adjustments += 1;
+                        self.adjusted = self.adjusted.saturating_add(amount);
+                    }
+                    // counted with the chargebacks that lock, above
+                    Applied::Lock => {}
+                    Applied::Unlock => self.accounts_unlocked += 1,
+                    Applied::Close => self.accounts_closed += 1,
+                }
+            }
+            Err(rejection) => {
+                self.rejected += 1;
+                *self.rejected_by_reason.entry(rejection.kind()).or_insert(0) += 1;
+                if let TxRejection::Overflow { .. } = rejection {
+                    self.overflows += 1;
+                }
+            }
+        }
+    }
+
+    /// adds the counts of `other`, e.g. from a worker of a parallel run
+    pub fn merge(&mut self, other: &Stats) {
+        self.transactions += other.transactions;
+        self.malformed += other.malformed;
+        self.applied += other.applied;
+        self.rejected += other.rejected;
+        for (reason, count) in &other.rejected_by_reason {
+            *self.rejected_by_reason.entry(reason).or_insert(0) += count;
+        }
+        self.deposits += other.deposits;
+        self.deposited = self.deposited.saturating_add(other.deposited);
+        self.withdrawals += other.withdrawals;
+        self.withdrawn = self.withdrawn.saturating_add(other.withdrawn);
+        self.disputes_opened += other.disputes_opened;
+        self.disputes_resolved += other.disputes_resolved;
+        self.chargebacks += other.chargebacks;
+        self.charged_back = self.charged_back.saturating_add(other.charged_back);
+        self.adjustments += other.adjustments;
+        self.adjusted = self.adjusted.saturating_add(other.adjusted);
+        self.accounts_created += other.accounts_created;
+        self.accounts_locked += other.accounts_locked;
+        self.accounts_unlocked += other.accounts_unlocked;
+        self.accounts_closed += other.accounts_closed;
+        self.overflows += other.overflows;
+        self.money_in = self.money_in.saturating_add(other.money_in);
+        self.money_out = self.money_out.saturating_add(other.money_out);
+    }
+
+    /// rows read: every transaction plus every malformed row
+    pub fn rows_read(&self) -> u64 {
+        self.transactions + self.malformed
+    }
+}
+
+/// The summary of a run: its `Stats` and the money still held, see `Accounts::summary`.
+#[derive(Serialize, Debug, Clone, Eq, PartialEq)]
+pub struct Summary {
+    pub rows_read: u64,
+    #[serde(flatten)]
+    pub stats: Stats,
+    /// money held by open disputes across all accounts, at the end of the run
+    pub money_held: Amount,
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let stats = &self.stats;
+        writeln!(
+            f,
+            "rows read: {} ({} transactions, {} malformed)",
+            self.rows_read, stats.transactions, stats.malformed
+        )?;
+        writeln!(f, "applied: {}", stats.applied)?;
+        writeln!(f, "rejected: {}", stats.rejected)?;
+        for (reason, count) in &stats.rejected_by_reason {
+            writeln!(f, "  {}: {}", reason, count)?;
+        }
+        writeln!(f, "deposits: {} ({})", stats.deposits, stats.deposited)?;
+        writeln!(
+            f,
+            "withdrawals: {} ({})",
+            stats.withdrawals, stats.withdrawn
+        )?;
+        writeln!(
+            f,
+            "disputes: {} opened, {} resolved, {} charged back ({})",
+            stats.disputes_opened, stats.disputes_resolved, stats.chargebacks, stats.charged_back
+        )?;
+        writeln!(f, "adjustments: {} ({})", stats.adjustments, stats.adjusted)?;
+        writeln!(
+            f,
+            "accounts: {} created, {} locked, {} unlocked, {} closed",
+            stats.accounts_created,
+            stats.accounts_locked,
+            stats.accounts_unlocked,
+            stats.accounts_closed
+        )?;
+        writeln!(f, "overflows: {}", stats.overflows)?;
+        write!(
+            f,
+            "money: {} in, {} out, {} held",
+            stats.money_in, stats.money_out, self.money_held
+        )
+    }
+}
@@ -0,0 +1,223 @@
+use crate::parser::amount::Amount;
+use crate::parser::transactions::{Transaction, TransactionType};
+use anyhow::{anyhow, Result};
+use redb::{Database, ReadableTable, TableDefinition};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Storage for the history of transactions that may later be disputed.
+/// Transactions are keyed by client id and tx id.
+pub trait TxStore {
+    /// looks up tx `tx_id` of client `client_id`
+    fn get(&self, client_id: u16, tx_id: u32) -> Result<Option<Transaction>>;
+    /// stores `tx`, replacing any transaction with the same client id and tx id
+    fn insert(&mut self, tx: Transaction) -> Result<()>;
+    /// calls `f` with every stored transaction, ordered by client id then tx id
+    fn for_each(&self, f: &mut dyn FnMut(Transaction) -> Result<()>) -> Result<()>;
+}
+
+/// Keeps every transaction in RAM. Fast, but memory grows with the input.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct InMemoryTxStore {
+    transactions: HashMap<(u16, u32), Transaction>,
+}
+
+impl InMemoryTxStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl TxStore for InMemoryTxStore {
+    fn get(&self, client_id: u16, tx_id: u32) -> Result<Option<Transaction>> {
+        Ok(self.transactions.get(&(client_id, tx_id)).copied())
+    }
+
+    fn insert(&mut self, tx: Transaction) -> Result<()> {
+        self.transactions.insert((tx.client_id, tx.tx_id), tx);
+        Ok(())
+    }
+
+    fn for_each(&self, f: &mut dyn FnMut(Transaction) -> Result<()>) -> Result<()> {
+        let mut keys: Vec<&(u16, u32)> = self.transactions.keys().collect();
+        keys.sort();
+        for key in keys {
+            f(self.transactions[key])?;
+        }
+        Ok(())
+    }
+}
+
+const TRANSACTIONS: TableDefinition<u64, [u8; RECORD_LEN]> = TableDefinition::new("transactions");
+/// number of inserts buffered in RAM before they are written to disk in one batch
+const WRITE_BUFFER_LEN: usize = 64 * 1024;
+/// size of redb's page cache
+const CACHE_BYTES: usize = 64 * 1024 * 1024;
+
+/// Keeps transactions in an embedded on-disk key-value store (redb).
+/// Memory use is bounded by the page cache and a small write buffer,
+/// regardless of how many transactions are stored.
+pub struct DiskTxStore {
+    db: Database,
+    // inserts not yet written to `db`
+    pending: HashMap<u64, Transaction>,
+}
+
+impl DiskTxStore {
+    /// creates a store at `path`, truncating any existing store
+    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        if path.exists() {
+            std::fs::remove_file(path)?;
+        }
+        let db = Database::builder()
+            .set_cache_size(CACHE_BYTES)
+            .create(path)?;
+        // make sure the table exists so reads never fail on an empty store
+        let write_txn = db.begin_write()?;
+        write_txn.open_table(TRANSACTIONS)?;
+        write_txn.commit()?;
+        Ok(DiskTxStore {
+            db,
+            pending: HashMap::new(),
+        })
+    }
+
+    /// writes all buffered inserts to disk
+    pub fn flush(&mut self) -> Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let write_txn = self.db.begin_write()?;
+        {
+            let mut table = write_txn.open_table(TRANSACTIONS)?;
+            for (key, tx) in self.pending.drain() {
+                table.insert(key, encode(&tx))?;
+            }
+        }
+        write_txn.commit()?;
+        Ok(())
+    }
+}
+
+impl TxStore for DiskTxStore {
+    fn get(&self, client_id: u16, tx_id: u32) -> Result<Option<Transaction>> {
+        let key = key(client_id, tx_id);
+        if let Some(tx) = self.pending.get(&key) {
+            return Ok(Some(*tx));
+        }
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(TRANSACTIONS)?;
+        let record = table.get(key)?;
+        record.map(|record| decode(&record.value())).transpose()
+    }
+
+    fn insert(&mut self, tx: Transaction) -> Result<()> {
+        self.pending.insert(key(tx.client_id, tx.tx_id), tx);
+        if self.pending.len() >= WRITE_BUFFER_LEN {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn for_each(&self, f: &mut dyn FnMut(Transaction) -> Result<()>) -> Result<()> {
+        // merge the sorted table with the (small) sorted write buffer
+        let mut pending: Vec<(&u64, &Transaction)> = self.pending.iter().collect();
+        pending.sort_by_key(|(key, _)| **key);
+        let mut pending = pending.into_iter().peekable();
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(TRANSACTIONS)?;
+        for entry in table.iter()? {
+            let (key, record) = entry?;
+            let key = key.value();
+            while let Some((_, tx)) = pending.next_if(|(pending_key, _)| **pending_key < key) {
+                f(*tx)?;
+            }
+            match pending.next_if(|(pending_key, _)| **pending_key == key) {
+                // the buffered insert replaces the one on disk
+                Some((_, tx)) => f(*tx)?,
+                None => f(decode(&record.value())?)?,
+            }
+        }
+        for (_, tx) in pending {
+            f(*tx)?;
+        }
+        Ok(())
+    }
+}
+
+fn key(client_id: u16, tx_id: u32) -> u64 {
+    (u64::from(client_id) << 32) | u64::from(tx_id)
+}
+
+/// type (1) + client (2) + tx (4) + has amount (1) + amount (16) + has reason (1) + reason (4)
+const RECORD_LEN: usize = 29;
+
+fn encode(tx: &Transaction) -> [u8; RECORD_LEN] {
+    let mut record = [0u8; RECORD_LEN];
+    record[0] = match tx.transaction_type {
+        TransactionType::Deposit => 0,
+        TransactionType::Withdrawal => 1,
+        TransactionType::Dispute => 2,
+        TransactionType::Resolve => 3,
+        TransactionType::Chargeback => 4,
+        TransactionType::Adjust => 5,
+        TransactionType::Lock => 6,
+        TransactionType::Unlock => 7,
+        TransactionType::Close => 8,
+    };
+    record[1..3].copy_from_slice(&tx.client_id.to_le_bytes());
+    record[3..7].copy_from_slice(&tx.tx_id.to_le_bytes());
+    if let Some(amount) = tx.amount {
+        record[7] = 1;
+        record[8..24].copy_from_slice(&amount.units().to_le_bytes());
+    }
+    if let Some(reason) = tx.reason {
+        record[24] = 1;
+        record[25..29].copy_from_slice(&reason.to_le_bytes());
+    }
+    record
+}
+
+fn decode(record: &[u8; RECORD_LEN]) -> Result<Transaction> {
+    let transaction_type = match record[0] {
+        0 => TransactionType::Deposit,
+        1 => TransactionType::Withdrawal,
+        2 => TransactionType::Dispute,
+        3 => TransactionType::Resolve,
+        4 => TransactionType::Chargeback,
+        5 => TransactionType::Adjust,
+        6 => TransactionType::Lock,
+        7 => TransactionType::Unlock,
+        8 => TransactionType::Close,
+        other => {
+            return Err(anyhow!(
+                "corrupt tx store: unknown transaction type {}",
+                other
+            ))
+        }
+    };
+    let mut client_id = [0u8; 2];
+    let mut tx_id = [0u8; 4];
+    let mut units = [0u8; 16];
+    let mut reason = [0u8; 4];
+    client_id.copy_from_slice(&record[1..3]);
+    tx_id.copy_from_slice(&record[3..7]);
+    units.copy_from_slice(&record[8..24]);
+    reason.copy_from_slice(&record[25..29]);
+    Ok(Transaction {
+        transaction_type,
+        client_id: u16::from_le_bytes(client_id),
+        tx_id: u32::from_le_bytes(tx_id),
+        amount: if record[7] == 1 {
+            Some(Amount::from_units(i128::from_le_bytes(units)))
+        } else {
+            None
+        },
+        reason: if record[24] == 1 {
+            Some(u32::from_le_bytes(reason))
+        } else {
+            None
+        },
+    })
+}
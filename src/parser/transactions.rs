@@ -1,17 +1,8 @@
-#![allow(clippy::unnecessary_unwrap)]
-use anyhow::Result;
-use fixed::{types::extra::U15, FixedI128};
-use serde::{
-    self,
-    de::{Error, Unexpected, Visitor},
-    Deserialize, Deserializer, Serialize,
-};
-use std::fmt;
-
-pub type FloatingPoint = FixedI128<U15>;
+use crate::parser::amount::Amount;
+use serde::{self, Deserialize, Serialize};
 
 /// represents the type of transaction.
-/// Currently there are five supported transaction types.
+/// Currently there are five supported transaction types, plus four admin ones.
 #[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
 #[serde(rename_all = "lowercase")]
 pub enum TransactionType {
@@ -20,28 +11,62 @@ pub enum TransactionType {
     Dispute,
     Resolve,
     Chargeback,
+    /// admin: a signed manual correction of the balance, with a reason code
+    Adjust,
+    /// admin: freezes the account
+    Lock,
+    /// admin: unfreezes the account, e.g. after a chargeback
+    Unlock,
+    /// admin: closes an empty account for good
+    Close,
 }
 
 impl TransactionType {
     /// The sign of the transaction
     /// Conceptually deposits "add" money to an account, so the sign is positive.
     /// Withdrawals "remove" money to an account, so the sign is negative.
+    /// Adjustments carry their own sign in the amount.
     /// The remainder of transaction types are noops.
-    pub fn get_sign(&self) -> FloatingPoint {
+    pub fn get_sign(&self) -> i128 {
         match self {
-            TransactionType::Deposit => FloatingPoint::from_num(1),
-            TransactionType::Withdrawal => FloatingPoint::from_num(-1),
+            TransactionType::Deposit | TransactionType::Adjust => 1,
+            TransactionType::Withdrawal => -1,
             // everything else is a noop
-            _ => FloatingPoint::from_num(0),
+            _ => 0,
         }
     }
-}
 
-/// HACK to get custom serde deserializer to work
-#[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
-pub struct FloatingPointWrap(
-    #[serde(deserialize_with = "deserialize_floating_point")] pub FloatingPoint,
-);
+    /// the name used in the input, e.g. `deposit`
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TransactionType::Deposit => "deposit",
+            TransactionType::Withdrawal => "withdrawal",
+            TransactionType::Dispute => "dispute",
+            TransactionType::Resolve => "resolve",
+            TransactionType::Chargeback => "chargeback",
+            TransactionType::Adjust => "adjust",
+            TransactionType::Lock => "lock",
+            TransactionType::Unlock => "unlock",
+            TransactionType::Close => "close",
+        }
+    }
+
+    /// whether this is an admin operation rather than a client transaction
+    pub fn is_admin(&self) -> bool {
+        matches!(
+            self,
+            TransactionType::Adjust
+                | TransactionType::Lock
+                | TransactionType::Unlock
+                | TransactionType::Close
+        )
+    }
+
+    /// whether a transaction of this type uses up its tx id, rather than referencing another
+    pub fn claims_tx_id(&self) -> bool {
+        matches!(self, TransactionType::Deposit | TransactionType::Withdrawal) || self.is_admin()
+    }
+}
 
 /// Transaction metadata
 #[derive(Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq)]
@@ -53,44 +78,43 @@ pub struct Transaction {
     #[serde(rename = "tx")]
     pub tx_id: u32,
     #[serde(rename = "amount")]
-    pub amount: Option<FloatingPointWrap>,
+    pub amount: Option<Amount>,
+    /// reason code of an admin operation
+    #[serde(rename = "reason", default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<u32>,
 }
 
 impl Transaction {
     /// Checks that the state makes sense for the type of transaction.
-    /// Specifically, only withdrawals and deposits can have an amount.
+    /// Specifically, withdrawals and deposits need an amount that is not negative,
+    /// adjustments a signed one; disputes,
+    /// resolves and chargebacks may have a positive one, to move only part of the disputed
+    /// transaction; the rest have none. Only admin operations can have a reason code.
+    /// An adjustment needs a non-zero amount and a reason code.
     pub fn check_state(&self) -> bool {
+        self.check_amount() && self.check_reason()
+    }
+
+    /// the part of `check_state` about the amount
+    pub fn check_amount(&self) -> bool {
         match self.transaction_type {
-            TransactionType::Deposit | TransactionType::Withdrawal => self.amount.is_some(),
+            TransactionType::Deposit | TransactionType::Withdrawal => {
+                self.amount.is_some_and(|amount| !amount.is_negative())
+            }
+            TransactionType::Adjust => self.amount.is_some_and(|amount| amount != Amount::ZERO),
             TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback => {
-                self.amount.is_none()
+                self.amount.is_none_or(|amount| amount > Amount::ZERO)
             }
+            _ => self.amount.is_none(),
         }
     }
-}
 
-pub fn deserialize_floating_point<'de, D>(deserializer: D) -> Result<FloatingPoint, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    deserializer.deserialize_str(FloatingPointVisitor)
-}
-
-struct FloatingPointVisitor;
-impl<'de> Visitor<'de> for FloatingPointVisitor {
-    type Value = FloatingPoint;
-    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-        formatter.write_str("a string representation of a floating point (up to 4 decimal places)")
-    }
-    fn visit_str<E>(self, value: &str) -> Result<FloatingPoint, E>
-    where
-        E: Error,
-    {
-        value
-            .parse::<f64>()
-            .map(FloatingPoint::from_num)
-            .map_err(|_err| {
-                E::invalid_value(Unexpected::Str(value), &"a string representation of a f64")
-            })
+    /// the part of `check_state` about the reason code
+    pub fn check_reason(&self) -> bool {
+        match self.transaction_type {
+            TransactionType::Adjust => self.reason.is_some(),
+            TransactionType::Lock | TransactionType::Unlock | TransactionType::Close => true,
+            _ => self.reason.is_none(),
+        }
     }
 }
@@ -0,0 +1,222 @@
+use crate::parser::accounts::{Account, Accounts, FatalError};
+use crate::parser::amount::Amount;
+use crate::parser::store::TxStore;
+use crate::parser::transactions::Transaction;
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+use thiserror::Error;
+
+/// A broken invariant found by `Accounts::verify`.
+#[derive(Error, Debug, Clone, Eq, PartialEq)]
+pub enum Violation {
+    #[error("client {client_id}: total {total} is not available {available} + held {held}")]
+    TotalMismatch {
+        client_id: u16,
+        available: Amount,
+        held: Amount,
+        total: Amount,
+    },
+    #[error("client {client_id}: disputed tx {tx_id} is not a stored transaction of the client")]
+    UnknownDispute { client_id: u16, tx_id: u32 },
+    #[error(
+        "client {client_id}: disputed tx {tx_id} has more held and charged back than its amount"
+    )]
+    ExcessDispute { client_id: u16, tx_id: u32 },
+    #[error("client {client_id}: held {held} is not the sum {disputed} of its open disputes")]
+    HeldMismatch {
+        client_id: u16,
+        held: Amount,
+        disputed: Amount,
+    },
+    #[error(
+        "client {client_id}: total {total} is not deposits less withdrawals plus adjustments \
+         {applied} less chargebacks {charged_back}"
+    )]
+    ClientConservation {
+        client_id: u16,
+        applied: Amount,
+        charged_back: Amount,
+        total: Amount,
+    },
+    #[error(
+        "sum of totals {totals} is not deposits less withdrawals plus adjustments {applied} \
+         less chargebacks {charged_back}"
+    )]
+    Conservation {
+        applied: Amount,
+        charged_back: Amount,
+        totals: Amount,
+    },
+    #[error("client {client_id}: balances overflow")]
+    Overflow { client_id: u16 },
+}
+
+impl<S: TxStore> Accounts<S> {
+    /// checks the invariants of every account and that no money appeared or vanished:
+    /// - total is available + held
+    /// - every open dispute references a stored transaction of the client
+    /// - held is the sum of the held parts of the open disputes, and no tx has more held
+    ///   and charged back than its amount
+    /// - total is the client's deposits less withdrawals plus adjustments less chargebacks,
+    ///   and the same for the sum over all accounts
+    ///
+    /// returns every violation found; errors only if the tx store fails.
+    pub fn verify(&self) -> Result<Vec<Violation>> {
+        let mut violations = vec![];
+        let mut client_ids: Vec<u16> = self.state.keys().copied().collect();
+        client_ids.sort_unstable();
+        for client_id in &client_ids {
+            violations.extend(self.verify_client(*client_id)?);
+        }
+
+        let applied = self.applied_by_client()?;
+        let (mut all_applied, mut all_charged_back, mut totals) =
+            (Amount::ZERO, Amount::ZERO, Amount::ZERO);
+        for client_id in client_ids {
+            let account = &self.state[&client_id];
+            let client_applied = applied.get(&client_id).copied().unwrap_or(Amount::ZERO);
+            let sums = (
+                all_applied.checked_add(client_applied),
+                all_charged_back.checked_add(account.charged_back),
+                totals.checked_add(account.total_bal),
+            );
+            match (sums, client_applied.checked_sub(account.charged_back)) {
+                ((Some(a), Some(c), Some(t)), Some(expected)) => {
+                    (all_applied, all_charged_back, totals) = (a, c, t);
+                    if expected != account.total_bal {
+                        violations.push(Violation::ClientConservation {
+                            client_id,
+                            applied: client_applied,
+                            charged_back: account.charged_back,
+                            total: account.total_bal,
+                        });
+                    }
+                }
+                _ => violations.push(Violation::Overflow { client_id }),
+            }
+        }
+        // transactions of clients without an account
+        for (client_id, client_applied) in applied {
+            if !self.state.contains_key(&client_id) {
+                match all_applied.checked_add(client_applied) {
+                    Some(sum) => all_applied = sum,
+                    None => violations.push(Violation::Overflow { client_id }),
+                }
+            }
+        }
+        if all_applied.checked_sub(all_charged_back) != Some(totals) {
+            violations.push(Violation::Conservation {
+                applied: all_applied,
+                charged_back: all_charged_back,
+                totals,
+            });
+        }
+        Ok(violations)
+    }
+
+    /// checks the invariants of the account of `client_id` that do not need the whole history:
+    /// every check of `verify` except conservation
+    pub fn verify_client(&self, client_id: u16) -> Result<Vec<Violation>> {
+        let mut violations = vec![];
+        let account = match self.state.get(&client_id) {
+            Some(account) => account,
+            None => return Ok(violations),
+        };
+        if account.avail_bal.checked_add(account.held_bal) != Some(account.total_bal) {
+            violations.push(Violation::TotalMismatch {
+                client_id,
+                available: account.avail_bal,
+                held: account.held_bal,
+                total: account.total_bal,
+            });
+        }
+        match self.disputed_amount(account, &mut violations)? {
+            Some(disputed) if disputed != account.held_bal => {
+                violations.push(Violation::HeldMismatch {
+                    client_id,
+                    held: account.held_bal,
+                    disputed,
+                })
+            }
+            Some(_) => {}
+            None => violations.push(Violation::Overflow { client_id }),
+        }
+        Ok(violations)
+    }
+
+    /// keeps a `FatalError::Invariant` if the account `t` applied to breaks an invariant,
+    /// or cannot be checked; see `Accounts::verify_each`
+    pub(crate) fn check_verified(&mut self, t: &Transaction) {
+        let reason = match self.verify_client(t.client_id) {
+            Ok(violations) if violations.is_empty() => return,
+            Ok(violations) => {
+                let violations: Vec<String> = violations.iter().map(Violation::to_string).collect();
+                violations.join("; ")
+            }
+            Err(err) => format!("cannot verify: {}", err),
+        };
+        self.fail(FatalError::Invariant {
+            client_id: t.client_id,
+            tx_id: t.tx_id,
+            reason,
+        });
+    }
+
+    /// the signed sum of every stored deposit, withdrawal and adjustment, by client
+    pub(crate) fn applied_by_client(&self) -> Result<HashMap<u16, Amount>> {
+        let mut applied = HashMap::new();
+        self.store.for_each(&mut |tx| {
+            let sum = applied.entry(tx.client_id).or_insert(Amount::ZERO);
+            *sum = tx
+                .amount
+                .and_then(|amount| amount.checked_mul(tx.transaction_type.get_sign()))
+                .and_then(|amount| sum.checked_add(amount))
+                .ok_or_else(|| anyhow!("deposits of client {} overflow", tx.client_id))?;
+            Ok(())
+        })?;
+        Ok(applied)
+    }
+
+    /// the signed sum of the parts of transactions `account` holds, or `None` on overflow.
+    /// Disputes of unknown transactions are added to `violations` and count as zero;
+    /// disputes holding more than is left of their transaction are added too.
+    fn disputed_amount(
+        &self,
+        account: &Account,
+        violations: &mut Vec<Violation>,
+    ) -> Result<Option<Amount>> {
+        let client_id = account.client_id;
+        let mut disputes: Vec<(u32, Amount)> = account
+            .disputes
+            .iter()
+            .map(|(tx_id, held)| (*tx_id, *held))
+            .collect();
+        disputes.sort_unstable();
+        let mut disputed = Amount::ZERO;
+        for (tx_id, held) in disputes {
+            let tx = match self.store.get(client_id, tx_id)? {
+                Some(tx) => tx,
+                None => {
+                    violations.push(Violation::UnknownDispute { client_id, tx_id });
+                    continue;
+                }
+            };
+            let charged_back = account.charged_back_txs.get(&tx_id).copied();
+            let within = held
+                .checked_add(charged_back.unwrap_or(Amount::ZERO))
+                .zip(tx.amount)
+                .is_some_and(|(moved, amount)| moved <= amount);
+            if !within {
+                violations.push(Violation::ExcessDispute { client_id, tx_id });
+            }
+            match held
+                .checked_mul(tx.transaction_type.get_sign())
+                .and_then(|amount| disputed.checked_add(amount))
+            {
+                Some(sum) => disputed = sum,
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(disputed))
+    }
+}